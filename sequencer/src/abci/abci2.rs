@@ -0,0 +1,379 @@
+//! ABCI 2.0 server for CometBFT 0.38, enabled with the `abci2` feature.
+//!
+//! CometBFT 0.38 replaces `BeginBlock`, `DeliverTx` and `EndBlock` with a single `FinalizeBlock`
+//! request, returns the app hash from it instead of from `Commit`, and lets validators attach
+//! extensions to their precommit votes. `StarknetApp` keeps implementing the hooks of
+//! `tendermint-abci`, and this server translates the 0.38 requests into them. The block is only
+//! persisted on `Commit`, so a node that stops after `FinalizeBlock` gets the block again on restart.
+
+use crate::application::StarknetApp;
+use anyhow::{bail, Result};
+use prost::Message;
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use tendermint_abci::Application;
+use tendermint_proto::abci;
+use tendermint_proto_v038::v0_38::abci::{
+    self as v0_38, request, response, response_verify_vote_extension,
+};
+use tracing::{debug, info, warn};
+
+/// Accept connections from CometBFT, serving each of them on its own thread.
+pub fn serve(address: &str, app: StarknetApp, read_buf_size: usize) -> Result<()> {
+    let listener = TcpListener::bind(address)?;
+    info!("ABCI 2.0 server listening on {address}");
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let peer = stream.peer_addr()?;
+        let app = app.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(&app, stream, read_buf_size) {
+                warn!("Error serving {peer}: {e}");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+fn handle_connection(app: &StarknetApp, stream: TcpStream, read_buf_size: usize) -> Result<()> {
+    let mut reader = BufReader::with_capacity(read_buf_size, stream.try_clone()?);
+    let mut writer = stream;
+
+    while let Some(request) = read_request(&mut reader)? {
+        let value = match request.value {
+            Some(value) => handle(app, value),
+            None => response::Value::Exception(v0_38::ResponseException {
+                error: "Empty request".to_string(),
+            }),
+        };
+        writer
+            .write_all(&v0_38::Response { value: Some(value) }.encode_length_delimited_to_vec())?;
+    }
+
+    Ok(())
+}
+
+/// Read a request prefixed by its length as a varint, or `None` if the connection was closed.
+fn read_request(reader: &mut impl Read) -> Result<Option<v0_38::Request>> {
+    let mut length = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8];
+        match reader.read_exact(&mut byte) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof && shift == 0 => return Ok(None),
+            result => result?,
+        }
+
+        length |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            let mut message = vec![0; usize::try_from(length)?];
+            reader.read_exact(&mut message)?;
+            return Ok(Some(v0_38::Request::decode(message.as_slice())?));
+        }
+    }
+
+    bail!("Request length is not a valid varint")
+}
+
+fn handle(app: &StarknetApp, request: request::Value) -> response::Value {
+    match request {
+        request::Value::Echo(request) => response::Value::Echo(v0_38::ResponseEcho {
+            message: request.message,
+        }),
+        request::Value::Flush(_) => response::Value::Flush(Default::default()),
+        request::Value::Info(request) => {
+            let info = app.info(abci::RequestInfo {
+                version: request.version,
+                block_version: request.block_version,
+                p2p_version: request.p2p_version,
+                abci_version: request.abci_version,
+            });
+            response::Value::Info(v0_38::ResponseInfo {
+                data: info.data,
+                version: info.version,
+                app_version: info.app_version,
+                last_block_height: info.last_block_height,
+                last_block_app_hash: info.last_block_app_hash,
+            })
+        }
+        request::Value::InitChain(request) => {
+            let init_chain = app.init_chain(abci::RequestInitChain {
+                chain_id: request.chain_id,
+                app_state_bytes: request.app_state_bytes,
+                initial_height: request.initial_height,
+                ..Default::default()
+            });
+            response::Value::InitChain(v0_38::ResponseInitChain {
+                app_hash: init_chain.app_hash,
+                ..Default::default()
+            })
+        }
+        request::Value::Query(request) => {
+            let query = app.query(abci::RequestQuery {
+                data: request.data,
+                path: request.path,
+                height: request.height,
+                prove: request.prove,
+            });
+            response::Value::Query(v0_38::ResponseQuery {
+                code: query.code,
+                log: query.log,
+                info: query.info,
+                index: query.index,
+                key: query.key,
+                value: query.value,
+                height: query.height,
+                codespace: query.codespace,
+                ..Default::default()
+            })
+        }
+        request::Value::CheckTx(request) => {
+            let check_tx = app.check_tx(abci::RequestCheckTx {
+                tx: request.tx,
+                r#type: request.r#type,
+            });
+            response::Value::CheckTx(v0_38::ResponseCheckTx {
+                code: check_tx.code,
+                data: check_tx.data,
+                log: check_tx.log,
+                info: check_tx.info,
+                gas_wanted: check_tx.gas_wanted,
+                gas_used: check_tx.gas_used,
+                events: events(check_tx.events),
+                codespace: check_tx.codespace,
+            })
+        }
+        request::Value::PrepareProposal(request) => {
+            let proposal = app.prepare_proposal(abci::RequestPrepareProposal {
+                max_tx_bytes: request.max_tx_bytes,
+                txs: request.txs,
+                height: request.height,
+                proposer_address: request.proposer_address,
+                ..Default::default()
+            });
+            response::Value::PrepareProposal(v0_38::ResponsePrepareProposal { txs: proposal.txs })
+        }
+        request::Value::ProcessProposal(request) => {
+            let proposal = app.process_proposal(abci::RequestProcessProposal {
+                txs: request.txs,
+                hash: request.hash,
+                height: request.height,
+                proposer_address: request.proposer_address,
+                ..Default::default()
+            });
+            // both versions number the proposal statuses the same way
+            response::Value::ProcessProposal(v0_38::ResponseProcessProposal {
+                status: proposal.status,
+            })
+        }
+        request::Value::FinalizeBlock(request) => {
+            response::Value::FinalizeBlock(finalize_block(app, request))
+        }
+        // the app hash was already returned when the block was finalized
+        request::Value::Commit(_) => {
+            app.persist();
+            response::Value::Commit(v0_38::ResponseCommit { retain_height: 0 })
+        }
+        // votes are not extended, so only empty extensions are valid
+        request::Value::ExtendVote(_) => response::Value::ExtendVote(Default::default()),
+        request::Value::VerifyVoteExtension(request) => {
+            let status = if request.vote_extension.is_empty() {
+                response_verify_vote_extension::VerifyStatus::Accept
+            } else {
+                warn!(
+                    "Rejecting vote extension of validator {} for height {}",
+                    hex::encode(&request.validator_address),
+                    request.height
+                );
+                response_verify_vote_extension::VerifyStatus::Reject
+            };
+            response::Value::VerifyVoteExtension(v0_38::ResponseVerifyVoteExtension {
+                status: status as i32,
+            })
+        }
+        // state sync is not supported
+        request::Value::ListSnapshots(_) => response::Value::ListSnapshots(Default::default()),
+        request::Value::OfferSnapshot(_) => response::Value::OfferSnapshot(Default::default()),
+        request::Value::LoadSnapshotChunk(_) => {
+            response::Value::LoadSnapshotChunk(Default::default())
+        }
+        request::Value::ApplySnapshotChunk(_) => {
+            response::Value::ApplySnapshotChunk(Default::default())
+        }
+    }
+}
+
+/// Run the block through the `begin_block`, `deliver_tx` and `end_block` hooks, and compute the
+/// resulting app hash without persisting the state, which waits for `Commit`. The application doesn't
+/// update validators or consensus params, so only the events and transaction results are returned.
+fn finalize_block(
+    app: &StarknetApp,
+    request: v0_38::RequestFinalizeBlock,
+) -> v0_38::ResponseFinalizeBlock {
+    debug!(
+        "Finalizing block {} with {} transactions",
+        request.height,
+        request.txs.len()
+    );
+
+    let begin_block = app.begin_block(abci::RequestBeginBlock {
+        hash: request.hash,
+        ..Default::default()
+    });
+    let tx_results = request
+        .txs
+        .into_iter()
+        .map(|tx| {
+            let deliver_tx = app.deliver_tx(abci::RequestDeliverTx { tx });
+            v0_38::ExecTxResult {
+                code: deliver_tx.code,
+                data: deliver_tx.data,
+                log: deliver_tx.log,
+                info: deliver_tx.info,
+                gas_wanted: deliver_tx.gas_wanted,
+                gas_used: deliver_tx.gas_used,
+                events: events(deliver_tx.events),
+                codespace: deliver_tx.codespace,
+            }
+        })
+        .collect();
+    let end_block = app.end_block(abci::RequestEndBlock {
+        height: request.height,
+    });
+    let app_hash = app.finalize();
+
+    let mut block_events = events(begin_block.events);
+    block_events.extend(events(end_block.events));
+
+    v0_38::ResponseFinalizeBlock {
+        events: block_events,
+        tx_results,
+        app_hash: app_hash.to_vec().into(),
+        ..Default::default()
+    }
+}
+
+fn events(events: Vec<abci::Event>) -> Vec<v0_38::Event> {
+    events
+        .into_iter()
+        .map(|event| v0_38::Event {
+            r#type: event.r#type,
+            attributes: event
+                .attributes
+                .into_iter()
+                .map(|attribute| v0_38::EventAttribute {
+                    key: attribute.key,
+                    value: attribute.value,
+                    index: attribute.index,
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Metrics;
+    use assert_fs::TempDir;
+    use lib::account::selector;
+    use lib::felt_to_hex;
+    use lib::{Transaction, TransactionType};
+    use starknet_rs::core::contract_address::starknet_contract_address::compute_class_hash;
+    use starknet_rs::services::api::contract_class::ContractClass;
+
+    /// Application with an account that accepts every transaction deployed at `0x100`.
+    fn app(dir: &TempDir) -> StarknetApp {
+        let metrics = Metrics::new().unwrap();
+        let app = StarknetApp::new(dir.path(), metrics).unwrap();
+        let account = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../cairo_programs/account_without_validation.json"
+        );
+        let program = std::fs::read_to_string(account).unwrap();
+        let class_hash = compute_class_hash(&ContractClass::try_from(program.as_str()).unwrap());
+        let genesis = serde_json::json!({
+            "classes": {
+                "account": { "path": account, "class_hash": felt_to_hex(&class_hash.unwrap()) }
+            },
+            "contracts": [{ "address": "0x100", "class": "account" }],
+        });
+        handle(
+            &app,
+            request::Value::InitChain(v0_38::RequestInitChain {
+                app_state_bytes: serde_json::to_vec(&genesis).unwrap().into(),
+                ..Default::default()
+            }),
+        );
+        app
+    }
+
+    fn info(app: &StarknetApp) -> v0_38::ResponseInfo {
+        match handle(app, request::Value::Info(Default::default())) {
+            response::Value::Info(info) => info,
+            response => panic!("Unexpected response {response:?}"),
+        }
+    }
+
+    #[test]
+    fn requests_are_read_until_the_connection_is_closed() {
+        let echo = v0_38::Request {
+            value: Some(request::Value::Echo(v0_38::RequestEcho {
+                message: "a".repeat(200),
+            })),
+        };
+        let flush = v0_38::Request {
+            value: Some(request::Value::Flush(v0_38::RequestFlush {})),
+        };
+        let mut bytes = echo.encode_length_delimited_to_vec();
+        bytes.extend(flush.encode_length_delimited_to_vec());
+
+        let mut reader = bytes.as_slice();
+        assert_eq!(read_request(&mut reader).unwrap(), Some(echo));
+        assert_eq!(read_request(&mut reader).unwrap(), Some(flush));
+        assert_eq!(read_request(&mut reader).unwrap(), None);
+
+        // a connection closed in the middle of a request is an error
+        assert!(read_request(&mut &bytes[..100]).is_err());
+    }
+
+    #[test]
+    fn finalized_blocks_are_persisted_on_commit() {
+        let dir = TempDir::new().unwrap();
+        let app = app(&dir);
+        let genesis = info(&app);
+        let transaction_type = TransactionType::Invoke {
+            address: 0x100.into(),
+            entry_point_selector: selector("assert_only_self"),
+            calldata: vec![],
+        };
+        let tx = Transaction::new(transaction_type, 0x100.into(), 0.into(), 0).unwrap();
+
+        let finalized = match handle(
+            &app,
+            request::Value::FinalizeBlock(v0_38::RequestFinalizeBlock {
+                txs: vec![bincode::serialize(&tx).unwrap().into()],
+                height: 1,
+                ..Default::default()
+            }),
+        ) {
+            response::Value::FinalizeBlock(finalized) => finalized,
+            response => panic!("Unexpected response {response:?}"),
+        };
+
+        assert_eq!(finalized.tx_results.len(), 1);
+        assert_eq!(finalized.tx_results[0].code, 0);
+        assert_ne!(finalized.app_hash, genesis.last_block_app_hash);
+        // nothing is persisted until the block is committed
+        assert_eq!(info(&app).last_block_height, 0);
+        assert_eq!(info(&app).last_block_app_hash, genesis.last_block_app_hash);
+
+        handle(&app, request::Value::Commit(Default::default()));
+
+        let committed = info(&app);
+        assert_eq!(committed.last_block_height, 1);
+        assert_eq!(committed.last_block_app_hash, finalized.app_hash);
+    }
+}
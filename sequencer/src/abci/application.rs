@@ -1,11 +1,20 @@
 use anyhow::bail;
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, ensure, Context, Result};
 use felt::Felt252;
-use lib::{Transaction, TransactionType};
-use num_traits::Num;
+use lib::account::{
+    selector, BALANCE_OF_ENTRY_POINT, CONSTRUCTOR_ENTRY_POINT, EXECUTE_ENTRY_POINT,
+    TRANSFER_ENTRY_POINT, VALIDATE_DECLARE_ENTRY_POINT, VALIDATE_DEPLOY_ENTRY_POINT,
+    VALIDATE_ENTRY_POINT,
+};
+use lib::block::StateUpdate;
+use lib::error::ErrorCode;
+use lib::execution::{self, ExecutionOutcome, ExecutionResources, Receipt};
+use lib::fee::{calculate_fee, FeeConfig, FeeWeights};
+use lib::hash::storage_var_address;
+use lib::query::{Query, QueryResponse};
+use lib::sierra::{CasmClass, SierraClass};
+use lib::{felt_to_hex, Transaction, TransactionType, TRANSACTION_VERSION};
 use num_traits::Zero;
-use once_cell::sync::Lazy;
-use sha2::{Digest, Sha256};
 use starknet_rs::business_logic::execution::execution_entry_point::ExecutionEntryPoint;
 use starknet_rs::business_logic::execution::objects::CallInfo;
 use starknet_rs::business_logic::execution::objects::CallType;
@@ -15,25 +24,25 @@ use starknet_rs::business_logic::fact_state::state::ExecutionResourcesManager;
 use starknet_rs::business_logic::state::state_api::State;
 use starknet_rs::business_logic::state::state_api::StateReader;
 
-use starknet_rs::business_logic::{
-    fact_state::in_memory_state_reader::InMemoryStateReader, state::cached_state::CachedState,
-};
 use starknet_rs::core::contract_address::starknet_contract_address::compute_class_hash;
 use starknet_rs::definitions::general_config::StarknetGeneralConfig;
 
 use starknet_rs::services::api::contract_class::EntryPointType;
 
+use starknet_rs::services::api::contract_class::ContractClass;
 use starknet_rs::utils::felt_to_hash;
-use starknet_rs::utils::string_to_hash;
 use starknet_rs::utils::Address;
-use starknet_rs::{
-    core::transaction_hash::starknet_transaction_hash::calculate_deploy_transaction_hash,
-    hash_utils::calculate_contract_address, services::api::contract_class::ContractClass,
-};
 use tracing::log::warn;
 
+use crate::genesis::{ChainConfig, Genesis, ERC20_BALANCES_STORAGE_VAR};
+use crate::metrics::Metrics;
+use crate::proposal::{decode_candidates, ExecutedBlock, OptimisticResults, ProposalQueue};
+use crate::state::{StarknetState, StateDiff};
+use crate::storage::Storage;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
     sync::{Arc, Mutex},
     time::Instant,
 };
@@ -50,24 +59,68 @@ use tracing::{debug, info};
 /// For reference see https://docs.tendermint.com/v0.34/introduction/what-is-tendermint.html#abci-overview
 #[derive(Debug, Clone)]
 pub struct StarknetApp {
-    hasher: Arc<Mutex<Sha256>>,
-    starknet_state: Arc<Mutex<CachedState<InMemoryStateReader>>>,
+    starknet_state: Arc<Mutex<StarknetState>>,
+    /// Receipts of the transactions delivered on the current block.
+    receipts: Arc<Mutex<Vec<Receipt>>>,
+    /// Transactions delivered on the current block so far, including the ones that could not be
+    /// executed, which is the position in the block of the next one.
+    delivered_txs: Arc<Mutex<usize>>,
+    /// Next nonce of the accounts with transactions waiting in the mempool. It is cleared on commit,
+    /// and rebuilt as CometBFT rechecks the transactions left in the mempool.
+    mempool_nonces: Arc<Mutex<HashMap<Address, Felt252>>>,
+    /// Blocks proposed for the current height that were accepted in `process_proposal`, by block hash.
+    proposals: Arc<Mutex<HashMap<Vec<u8>, ExecutedBlock>>>,
+    /// Results of the block being delivered, if it was executed when it was proposed.
+    optimistic: Arc<Mutex<Option<OptimisticResults>>>,
+    /// Block whose app hash was computed by `finalize`, waiting to be persisted by `persist`.
+    finalized: Arc<Mutex<Option<FinalizedBlock>>>,
+    storage: Storage,
     config: StarknetGeneralConfig,
+    /// Fee settings and step budget of blocks, set by the genesis.
+    chain_config: Arc<Mutex<ChainConfig>>,
+    /// Weights of the Cairo resources in the L1 gas a transaction is charged for.
+    fee_weights: FeeWeights,
+    metrics: Metrics,
 }
 
-// because we don't get a `&mut self` in the ABCI API, we opt to have a mod-level variable
-// and because beginblock, endblock and deliver_tx all happen in the same thread, this is safe to do
-// an alternative would be Arc<Mutex<>>, but we want to avoid extra-overhead of locks for the benchmark's sake
-static mut TRANSACTIONS: usize = 0;
-static mut TIMER: Lazy<Instant> = Lazy::new(Instant::now);
+/// Everything a finalized block writes to storage once it's committed.
+#[derive(Debug)]
+struct FinalizedBlock {
+    height: i64,
+    diff: StateDiff,
+    receipts: Vec<Receipt>,
+    state_update: StateUpdate,
+    app_hash: [u8; 32],
+}
 
 impl Application for StarknetApp {
-    /// This hook is called once upon genesis. It's used to load a default set of records which
-    /// make the initial distribution of credits in the system.
-    fn init_chain(&self, _request: abci::RequestInitChain) -> abci::ResponseInitChain {
+    /// This hook is called once upon genesis. It's used to load the initial Starknet state described
+    /// by the genesis app state: declared classes, deployed contracts, storage and fee token balances.
+    fn init_chain(&self, request: abci::RequestInitChain) -> abci::ResponseInitChain {
+        // CometBFT calls this again if the node restarts before the first block is committed
+        let app_hash = self
+            .storage
+            .app_hash()
+            .expect("Could not read last app hash");
+        if !app_hash.is_empty() {
+            info!("Genesis already loaded");
+            return abci::ResponseInitChain {
+                app_hash: app_hash.into(),
+                ..Default::default()
+            };
+        }
+
         info!("Loading genesis");
 
-        Default::default()
+        // a node that can't load the genesis can't follow the chain, so we crash intentionally
+        let genesis =
+            Genesis::from_bytes(&request.app_state_bytes).expect("Could not parse genesis");
+        let app_hash = self.load_genesis(genesis).expect("Could not load genesis");
+
+        abci::ResponseInitChain {
+            app_hash: app_hash.to_vec().into(),
+            ..Default::default()
+        }
     }
 
     /// This hook provides information about the ABCI application.
@@ -81,20 +134,28 @@ impl Application for StarknetApp {
             data: "cairo-app".to_string(),
             version: "0.1.0".to_string(),
             app_version: 1,
-            last_block_height: HeightFile::read_or_create(),
-
-            // using a fixed hash, see the commit() hook
-            last_block_app_hash: vec![].into(),
+            last_block_height: self
+                .storage
+                .height()
+                .expect("Could not read last block height"),
+            last_block_app_hash: self
+                .storage
+                .app_hash()
+                .expect("Could not read last app hash")
+                .into(),
         }
     }
 
     /// This hook is to query the application for data at the current or past height.
-    fn query(&self, _request: abci::RequestQuery) -> abci::ResponseQuery {
-        let query_result = Err("Query hook needs implementation");
+    fn query(&self, request: abci::RequestQuery) -> abci::ResponseQuery {
+        let query_result = Query::from_request(&request.path, &request.data)
+            .and_then(|query| self.run_query(query))
+            .and_then(|response| response.encode());
 
         match query_result {
             Ok(value) => abci::ResponseQuery {
-                value,
+                value: value.into(),
+                height: self.storage.height().unwrap_or_default(),
                 ..Default::default()
             },
             Err(e) => abci::ResponseQuery {
@@ -109,38 +170,51 @@ impl Application for StarknetApp {
     /// This ABCI hook validates an incoming transaction before inserting it in the
     /// mempool and relaying it to other nodes.
     fn check_tx(&self, request: abci::RequestCheckTx) -> abci::ResponseCheckTx {
-        let tx: Transaction = bincode::deserialize(&request.tx).unwrap();
+        match self.validate_tx(&request.tx) {
+            Ok(tx) => {
+                match tx.transaction_type {
+                    TransactionType::Declare { program: _ } => {
+                        info!("Received declare transaction")
+                    }
+                    TransactionType::DeclareV2 { .. } => info!("Received declare v2 transaction"),
+                    TransactionType::DeployAccount { .. } => info!("Received deploy transaction"),
+                    TransactionType::Invoke { .. } => info!("Received invoke transaction"),
+                }
 
-        // TODO: Implement starknet validations for each one
-        match tx.transaction_type {
-            TransactionType::Declare { program: _ } => info!("Received declare transaction"),
-            TransactionType::DeployAccount { .. } => info!("Received deploy transaction"),
-            TransactionType::Invoke { .. } => info!("Received invoke transaction"),
-        }
+                abci::ResponseCheckTx {
+                    ..Default::default()
+                }
+            }
+            Err((code, error)) => {
+                warn!("Rejected transaction ({}): {}", code, error);
 
-        abci::ResponseCheckTx {
-            ..Default::default()
+                abci::ResponseCheckTx {
+                    code: code.into(),
+                    log: error.clone(),
+                    info: error,
+                    ..Default::default()
+                }
+            }
         }
     }
 
     /// This hook is called before the app starts processing transactions on a block.
     /// Used to store current proposer and the previous block's voters to assign fees and coinbase
     /// credits when the block is committed.
-    fn begin_block(&self, _request: abci::RequestBeginBlock) -> abci::ResponseBeginBlock {
-        // because begin_block, [deliver_tx] and end_block/commit are on the same thread, this is safe to do (see declaration of statics)
-
-        unsafe {
-            if TRANSACTIONS > 0 {
-                info!(
-                    "{} ms passed between begin_block() calls. {} transactions, {} tps",
-                    (*TIMER).elapsed().as_millis(),
-                    TRANSACTIONS,
-                    (TRANSACTIONS * 1000) as f32 / ((*TIMER).elapsed().as_millis() as f32)
-                );
-            }
-            TRANSACTIONS = 0;
-
-            *TIMER = Instant::now();
+    /// If the block was executed in `process_proposal`, its resulting state is adopted here and its
+    /// transactions are not executed again.
+    fn begin_block(&self, request: abci::RequestBeginBlock) -> abci::ResponseBeginBlock {
+        // the other proposals of the height won't be finalized
+        let executed =
+            std::mem::take(&mut *self.proposals.lock().unwrap()).remove(request.hash.as_ref());
+        self.metrics.begin_block(executed.is_some());
+        if let Some(block) = executed {
+            debug!(
+                "Reusing the execution of block {}",
+                hex::encode(request.hash.as_ref())
+            );
+            *self.starknet_state.lock().unwrap() = block.state;
+            *self.optimistic.lock().unwrap() = Some(OptimisticResults::new(block.results));
         }
 
         Default::default()
@@ -150,144 +224,147 @@ impl Application for StarknetApp {
     /// for example storing the program verifying keys upon a valid deployment.
     /// Here is also where transactions are indexed for querying the blockchain.
     fn deliver_tx(&self, request: abci::RequestDeliverTx) -> abci::ResponseDeliverTx {
-        let tx: Transaction = bincode::deserialize(&request.tx).unwrap();
+        let started = Instant::now();
+        let index = self
+            .delivered_txs
+            .lock()
+            .map(|mut delivered| {
+                *delivered += 1;
+                *delivered - 1
+            })
+            .unwrap();
+        let tx: Transaction = match bincode::deserialize(&request.tx) {
+            Ok(tx) => tx,
+            Err(e) => {
+                warn!("Could not decode delivered transaction: {e}");
+                self.metrics.deliver_tx("malformed", Some(started), 0, true);
 
-        // Validation consists of getting the hash and checking whether it is equal
-        // to the tx id. The hash executes the program and hashes the trace.
+                return abci::ResponseDeliverTx {
+                    code: ErrorCode::MalformedTransaction.into(),
+                    log: format!("Could not decode transaction: {e}"),
+                    info: format!("Could not decode transaction: {e}"),
+                    ..Default::default()
+                };
+            }
+        };
 
-        let tx_hash = tx
-            .transaction_type
-            .compute_and_hash()
-            .map(|x| x == tx.transaction_hash);
+        // Validation consists of recomputing the transaction hash and checking whether it is equal
+        // to the one it was signed with. The signature is checked by the sender account.
+        let integrity = tx.assert_integrity();
 
-        // because begin_block, [deliver_tx] and end_block/commit are on the same thread, this is safe to do (see declaration of statics)
-        unsafe {
-            TRANSACTIONS += 1;
-        }
+        match integrity {
+            Ok(()) => {
+                let (code, outcome, executed) = match self.optimistic_result(&tx) {
+                    Some((code, outcome)) => (code, outcome, false),
+                    None => {
+                        let result = self
+                            .starknet_state
+                            .lock()
+                            .map(|mut state| self.execute_tx(&mut state, &tx))
+                            .unwrap();
+                        let (code, outcome) = delivery_result(result);
+                        (code, outcome, true)
+                    }
+                };
+                let data = outcome.encode().unwrap_or_default();
+                let log = outcome.revert_reason.clone().unwrap_or_default();
+                let steps = outcome.steps();
+                if code != 0 {
+                    warn!("Transaction {} failed: {}", tx.id, log);
+                } else {
+                    info!(
+                        "Delivered {} transaction {} in {} steps",
+                        tx.transaction_type.name(),
+                        tx.transaction_hash,
+                        steps
+                    );
+                }
+                self.metrics.deliver_tx(
+                    tx.transaction_type.name(),
+                    executed.then_some(started),
+                    steps,
+                    code != 0,
+                );
 
-        match tx_hash {
-            Ok(true) => {
-                let _ = self
-                    .hasher
-                    .lock()
-                    .map(|mut hash| hash.update(tx.transaction_hash.clone()));
+                // prepare this transaction to be queried by app.tx_id, and its outcome by app.status
+                let mut attributes = vec![
+                    abci::EventAttribute {
+                        key: "tx_id".to_string(),
+                        value: tx.transaction_hash.to_string(),
+                        index: true,
+                    },
+                    abci::EventAttribute {
+                        key: "status".to_string(),
+                        value: outcome.status.as_str().to_string(),
+                        index: true,
+                    },
+                    abci::EventAttribute {
+                        key: "steps".to_string(),
+                        value: steps.to_string(),
+                        index: false,
+                    },
+                ];
+
+                // deployed contracts and declared classes can be looked up by app.contract_address and app.class_hash
+                if let Some(contract_address) = &outcome.contract_address {
+                    attributes.push(abci::EventAttribute {
+                        key: "contract_address".to_string(),
+                        value: contract_address.clone(),
+                        index: true,
+                    });
+                }
+                if let Some(class_hash) = &outcome.class_hash {
+                    attributes.push(abci::EventAttribute {
+                        key: "class_hash".to_string(),
+                        value: class_hash.clone(),
+                        index: true,
+                    });
+                }
 
-                // prepare this transaction to be queried by app.tx_id
                 let index_event = abci::Event {
                     r#type: "app".to_string(),
-                    attributes: vec![abci::EventAttribute {
-                        key: "tx_id".to_string(),
-                        value: tx.transaction_hash.to_string(), // TODO: Add more useful metadata
-                        index: true,
-                    }],
+                    attributes,
                 };
                 let events = vec![index_event];
 
-                match tx.transaction_type {
-                    TransactionType::Declare { program } => {
-                        let contract_class = ContractClass::try_from(program.as_str())
-                            .expect("Could not load contract from payload");
-                        // TODO: Maybe we can get contract_hash as part of the TransactionType and validate it instead of recalculating on each step
-                        // This function requires cairo_programs/contracts.json to exist as it uses that cairo program to compute the hash
-                        let contract_hash_felt = compute_class_hash(&contract_class).unwrap();
-                        let contract_hash = felt_to_hash(&contract_hash_felt);
-
-                        self.starknet_state
-                            .lock()
-                            .map(|mut state| {
-                                state
-                                    .set_contract_class(&contract_hash, &contract_class)
-                                    .unwrap()
-                            })
-                            .unwrap();
+                // the receipt is stored once the block is committed
+                self.receipts
+                    .lock()
+                    .map(|mut receipts| {
+                        let block_height = self
+                            .storage
+                            .height()
+                            .expect("Could not read last block height")
+                            + 1;
+                        receipts.push(Receipt {
+                            transaction_hash: tx.transaction_hash.clone(),
+                            block_height,
+                            index,
+                            outcome,
+                        })
+                    })
+                    .unwrap();
 
-                        // TODO: Should we send an event about this?
-                        info!("Declared tx_id: {}", tx.id);
-                        info!("Class Hash 0x{}", hex::encode(contract_hash),);
-                    }
-                    TransactionType::DeployAccount {
-                        class_hash,
-                        salt,
-                        inputs,
-                    } => {
-                        let constructor_calldata = match &inputs {
-                            Some(vec) => vec.iter().map(|&n| n.into()).collect(),
-                            None => Vec::new(),
-                        };
-                        let address = calculate_contract_address(
-                            &Address(salt.into()),
-                            &Felt252::from_str_radix(&class_hash[2..], 16).unwrap(), // TODO: Handle these errors better
-                            &constructor_calldata,
-                            Address(0.into()),
-                        )
-                        .unwrap();
-
-                        let _ = self.starknet_state.lock().map(|mut state| {
-                            // let class = state
-                            //     .get_contract_class(&felt_to_hash(&address.clone()))
-                            //     .unwrap();
-                            state.deploy_contract(
-                                Address(address.clone()),
-                                string_to_hash(&class_hash),
-                            )
-                        });
-
-                        let tx_hash = calculate_deploy_transaction_hash(
-                            0, // TODO: How are versions handled?
-                            &Address(address.clone()),
-                            &constructor_calldata,
-                            Felt252::zero(),
-                        )
-                        .unwrap();
-
-                        info!(
-                            "Deployed tx_id {}, Address: {}, tx_hash: {}",
-                            tx.id, address, tx_hash
-                        );
-                    }
-                    TransactionType::Invoke {
-                        address,
-                        function,
-                        inputs,
-                    } => match self.run_invoke_tx(&address, &function, &inputs) {
-                        Ok(result) => {
-                            info!(
-                                "Invoked tx_id {}, Address: {}, function: {}, inputs: {:?}",
-                                tx.id, address, function, inputs,
-                            );
-                            info!("Result: {:?}", result)
-                        }
-                        Err(error) => {
-                            warn!(
-                                    "Invoke failed for tx_id {}, Address: {}, function: {}, inputs: {:?}",
-                                    tx.id,
-                                    address,
-                                    function,
-                                    inputs,
-                                );
-                            warn!("Error: {:?}", error)
-                        }
-                    },
+                abci::ResponseDeliverTx {
+                    code,
+                    log,
+                    gas_used: steps as i64,
+                    data: data.into(),
+                    events,
+                    ..Default::default()
                 }
+            }
+            Err(e) => {
+                self.metrics
+                    .deliver_tx(tx.transaction_type.name(), Some(started), 0, true);
 
                 abci::ResponseDeliverTx {
-                    events,
-                    data: tx.transaction_hash.into(),
+                    code: ErrorCode::IntegrityCheckFailed.into(),
+                    log: format!("Error delivering transaction: {e}"),
+                    info: format!("Error delivering transaction: {e}"),
                     ..Default::default()
                 }
             }
-            Ok(false) => abci::ResponseDeliverTx {
-                code: 1,
-                log: "Error delivering transaction. Integrity check failed.".to_string(),
-                info: "Error delivering transaction. Integrity check failed.".to_string(),
-                ..Default::default()
-            },
-            Err(e) => abci::ResponseDeliverTx {
-                code: 1,
-                log: format!("Error delivering transaction: {e}"),
-                info: format!("Error delivering transaction: {e}"),
-                ..Default::default()
-            },
         }
     }
 
@@ -295,15 +372,13 @@ impl Application for StarknetApp {
     /// For details about validator set update semantics see:
     /// https://github.com/tendermint/tendermint/blob/v0.34.x/spec/abci/apps.md#endblock
     fn end_block(&self, _request: abci::RequestEndBlock) -> abci::ResponseEndBlock {
-        // because begin_block, [deliver_tx] and end_block/commit are on the same thread, this is safe to do (see declaration of statics)
-        unsafe {
-            info!(
-                "Committing block with {} transactions in {} ms. TPS: {}",
-                TRANSACTIONS,
-                (*TIMER).elapsed().as_millis(),
-                (TRANSACTIONS * 1000) as f32 / ((*TIMER).elapsed().as_millis() as f32)
-            );
-        }
+        let (transactions, elapsed_ms) = self.metrics.end_block();
+        info!(
+            "Committing block with {} transactions in {} ms. TPS: {}",
+            transactions,
+            elapsed_ms,
+            (transactions * 1000) as f32 / (elapsed_ms as f32)
+        );
         abci::ResponseEndBlock {
             ..Default::default()
         }
@@ -321,191 +396,1817 @@ impl Application for StarknetApp {
         // https://github.com/tendermint/tendermint/issues/1179
         // https://github.com/tendermint/tendermint/blob/v0.34.x/spec/abci/apps.md#query-proofs
 
-        let app_hash = self
-            .hasher
-            .lock()
-            .map(|hasher| hasher.clone().finalize().as_slice().to_vec());
-
-        let height = HeightFile::increment();
+        // here the app hash is a commitment over the resulting Starknet state, so nodes that execute a block
+        // differently end up with different hashes and fork instead of silently diverging.
 
-        info!("Committing height {}", height,);
+        let app_hash = self.finalize();
+        self.persist();
 
-        match app_hash {
-            Ok(hash) => abci::ResponseCommit {
-                data: hash.into(),
-                retain_height: 0,
-            },
-            // error should be handled here
-            _ => abci::ResponseCommit {
-                data: vec![].into(),
-                retain_height: 0,
-            },
+        abci::ResponseCommit {
+            data: app_hash.to_vec().into(),
+            retain_height: 0,
         }
     }
 
     /// A stage where the application can modify the list of transactions
     /// in the preliminary proposal.
     ///
-    /// The default implementation implements the required behavior in a
-    /// very naive way, removing transactions off the end of the list
-    /// until the limit on the total size of the transaction is met as
-    /// specified in the `max_tx_bytes` field of the request, or there are
-    /// no more transactions. It's up to the application to implement
-    /// more elaborate removal strategies.
+    /// Transactions that can't be decoded, fail their integrity check or repeat a hash are left out.
+    /// The rest are tried in proposal order (see `ProposalQueue`) against a copy of the committed state,
+    /// and those that would be rejected when executed, for example for using a stale nonce or an
+    /// unknown class, are left out too. The proposal stops growing a sender's transactions once one
+    /// of them doesn't fit within `max_tx_bytes` or the Cairo step budget of the block.
     ///
     /// This method is introduced in ABCI++.
     fn prepare_proposal(&self, request: RequestPrepareProposal) -> ResponsePrepareProposal {
-        // Per the ABCI++ spec: if the size of RequestPrepareProposal.txs is
-        // greater than RequestPrepareProposal.max_tx_bytes, the Application
-        // MUST remove transactions to ensure that the
-        // RequestPrepareProposal.max_tx_bytes limit is respected by those
-        // transactions returned in ResponsePrepareProposal.txs.
-        let RequestPrepareProposal {
-            mut txs,
-            max_tx_bytes,
-            ..
-        } = request;
-        let max_tx_bytes: usize = max_tx_bytes.try_into().unwrap_or(0);
-        let mut total_tx_bytes: usize = txs
-            .iter()
-            .map(|tx| tx.len())
-            .fold(0, |acc, len| acc.saturating_add(len));
-        while total_tx_bytes > max_tx_bytes {
-            if let Some(tx) = txs.pop() {
-                total_tx_bytes = total_tx_bytes.saturating_sub(tx.len());
-            } else {
-                break;
+        let max_tx_bytes: usize = request.max_tx_bytes.try_into().unwrap_or(0);
+        let mut queue = ProposalQueue::new(decode_candidates(&request.txs));
+        let mut state = self.starknet_state.lock().unwrap().snapshot();
+        let max_block_steps = self.max_block_steps();
+
+        let mut txs = Vec::new();
+        let (mut total_tx_bytes, mut total_steps) = (0, 0);
+        while let Some(candidate) = queue.pop() {
+            // a transaction whose nonce was already used is left out, without holding back the
+            // transactions of its sender that come after it
+            let sender_address = Address(candidate.transaction.sender_address.clone());
+            let nonce = match state.get_nonce_at(&sender_address) {
+                Ok(nonce) => nonce.clone(),
+                Err(_) => continue,
+            };
+            if candidate.transaction.nonce < nonce {
+                continue;
+            }
+
+            let checkpoint = state.checkpoint();
+            let included = self
+                .try_execute(&mut state, &candidate.transaction)
+                .filter(|steps| {
+                    total_tx_bytes + candidate.bytes.len() <= max_tx_bytes
+                        && total_steps + steps <= max_block_steps
+                });
+
+            match included {
+                Some(steps) => {
+                    total_tx_bytes += candidate.bytes.len();
+                    total_steps += steps;
+                    txs.push(candidate.bytes.into());
+                }
+                // the transactions of the sender after a nonce gap, a rejection or a transaction that
+                // doesn't fit can't be included either
+                None => {
+                    state.rollback(checkpoint);
+                    queue.skip_sender(&candidate);
+                }
             }
         }
+
+        debug!(
+            "Proposing {} of {} transactions, with {} bytes and {} steps",
+            txs.len(),
+            request.txs.len(),
+            total_tx_bytes,
+            total_steps
+        );
         ResponsePrepareProposal { txs }
     }
 
     /// A stage where the application can accept or reject the proposed block.
     ///
-    /// The default implementation returns the status value of `ACCEPT`.
+    /// Proposals are rejected unless they could have been built by `prepare_proposal`, so a byzantine
+    /// proposer can't make the network execute transactions that don't decode, are corrupted, are repeated,
+    /// skip or reuse nonces of their sender, are rejected by their sender, or that together exceed the step
+    /// budget of a block.
     ///
     /// This method is introduced in ABCI++.
-    fn process_proposal(&self, _request: RequestProcessProposal) -> ResponseProcessProposal {
+    fn process_proposal(&self, request: RequestProcessProposal) -> ResponseProcessProposal {
+        let started = Instant::now();
+        let status = match self.check_proposal(&request.txs) {
+            Ok(block) => {
+                self.metrics.process_proposal(started);
+                self.proposals
+                    .lock()
+                    .unwrap()
+                    .insert(request.hash.to_vec(), block);
+                response_process_proposal::ProposalStatus::Accept
+            }
+            Err(e) => {
+                warn!("Rejecting proposal for height {}: {e}", request.height);
+                response_process_proposal::ProposalStatus::Reject
+            }
+        };
+
         ResponseProcessProposal {
-            status: response_process_proposal::ProposalStatus::Accept as i32,
+            status: status as i32,
         }
     }
 }
 
 impl StarknetApp {
-    /// Constructor.
-    pub fn new() -> Self {
+    /// Constructor. Opens the state database under `data_dir` and resumes from the last committed block.
+    pub fn new(data_dir: &Path, metrics: Metrics) -> Result<Self> {
+        let storage = Storage::open(data_dir)?;
+        let height = storage.height()?;
+        let state = storage.load_state()?;
+        let chain_config = storage.chain_config()?;
+        metrics.commit(height, state.committed());
+
+        let config = StarknetGeneralConfig::default();
         let new_state = Self {
-            hasher: Arc::new(Mutex::new(Sha256::new())),
-            starknet_state: Arc::new(Mutex::new(CachedState::new(
-                InMemoryStateReader::default(),
-                Some(HashMap::new()),
-            ))),
-            config: StarknetGeneralConfig::default(),
+            starknet_state: Arc::new(Mutex::new(state)),
+            receipts: Arc::new(Mutex::new(Vec::new())),
+            delivered_txs: Arc::new(Mutex::new(0)),
+            mempool_nonces: Arc::new(Mutex::new(HashMap::new())),
+            proposals: Arc::new(Mutex::new(HashMap::new())),
+            optimistic: Arc::new(Mutex::new(None)),
+            finalized: Arc::new(Mutex::new(None)),
+            storage,
+            chain_config: Arc::new(Mutex::new(chain_config)),
+            fee_weights: FeeWeights::new(config.cairo_resource_fee_weights()),
+            config,
+            metrics,
         };
 
-        let height_file = HeightFile::read_or_create();
-
         info!(
-            "Starting with Starknet State: {:?}. Height file has value: {}",
-            new_state.starknet_state, height_file
+            "Starting with Starknet State: {:?}. Last committed height: {}",
+            new_state.starknet_state, height
         );
-        new_state
+        Ok(new_state)
     }
 
-    fn run_invoke_tx(
-        &self,
-        address: &String,
-        function: &String,
-        inputs: &Option<Vec<i32>>,
-    ) -> Result<CallInfo> {
-        let contract_address = Felt252::from_str_radix(&address[2..], 16)
-            .map_err(|_| anyhow!("Could not parse address: {}", address))?;
+    /// Apply the genesis state and commit it at height zero, returning the resulting app hash.
+    fn load_genesis(&self, genesis: Genesis) -> Result<[u8; 32]> {
+        let chain_config = genesis.chain_config();
+        let mut state = self.starknet_state.lock().unwrap();
 
-        let calldata: Vec<Felt252> = match &inputs {
-            Some(vec) => vec.iter().map(|&n| n.into()).collect(),
-            None => Vec::new(),
-        };
+        let mut class_hashes = HashMap::new();
+        for (name, class) in genesis.classes.iter() {
+            let program = fs::read_to_string(&class.path).with_context(|| {
+                format!("Could not read class {name} at {}", class.path.display())
+            })?;
+            let contract_class = ContractClass::try_from(program.as_str())
+                .map_err(|e| anyhow!("Could not parse class {name}: {e}"))?;
+            let class_hash = compute_class_hash(&contract_class)?;
+            // nodes read classes from their own disk, so they would diverge on different files
+            ensure!(
+                class_hash == class.class_hash,
+                "Class {name} at {} has hash {}, but the genesis expects {}",
+                class.path.display(),
+                felt_to_hex(&class_hash),
+                felt_to_hex(&class.class_hash)
+            );
+
+            info!(
+                "Declared genesis class {}: {}",
+                name,
+                felt_to_hex(&class_hash)
+            );
+            state.declare_class(felt_to_hash(&class_hash), program, contract_class);
+            class_hashes.insert(name, class_hash);
+        }
+
+        for contract in genesis.contracts {
+            let class_hash = class_hashes
+                .get(&contract.class)
+                .ok_or_else(|| anyhow!("Unknown genesis class {}", contract.class))?;
+            state.deploy_contract(Address(contract.address.clone()), felt_to_hash(class_hash))?;
+            self.run_constructor(
+                &mut state,
+                &contract.address,
+                &contract.constructor_calldata,
+                &self.call_context(),
+            )
+            .with_context(|| {
+                format!(
+                    "Could not deploy genesis contract at {}",
+                    felt_to_hex(&contract.address)
+                )
+            })?;
+            info!(
+                "Deployed genesis contract at {}",
+                felt_to_hex(&contract.address)
+            );
+        }
+
+        for entry in genesis.storage {
+            state.set_storage_at(
+                &(Address(entry.address), felt_to_hash(&entry.key)),
+                entry.value,
+            );
+        }
+
+        if let Some(fee_token) = genesis.fee_token {
+            for (account, amount) in fee_token.balances()? {
+                // balances are Uint256, the low 128 bits are stored first and the high ones are left as zero
+                let key = storage_var_address(ERC20_BALANCES_STORAGE_VAR, &[account]);
+                state.set_storage_at(
+                    &(Address(fee_token.address.clone()), felt_to_hash(&key)),
+                    amount.into(),
+                );
+            }
+        }
+
+        self.storage.set_chain_config(&chain_config)?;
+        *self.chain_config.lock().unwrap() = chain_config;
 
-        let result = self
+        let diff = state.commit();
+        let app_hash = state.commitment();
+        let state_update = state_update(0, &diff, &[], &[], &app_hash);
+        self.storage
+            .commit(0, &diff, &[], &state_update, &app_hash)?;
+
+        Ok(app_hash)
+    }
+
+    /// Answer a query using the committed state only, so results are not affected by the block being executed.
+    /// Nonces also count the transactions waiting in the mempool, so clients can build their next transaction.
+    fn run_query(&self, query: Query) -> Result<QueryResponse> {
+        let state = self
             .starknet_state
             .lock()
-            .map(|mut state| {
-                let class_hash = *state
-                    .get_class_hash_at(&Address(contract_address.clone()))
-                    .unwrap();
+            .map_err(|_| anyhow!("Could not lock Starknet state"))?;
 
-                // check if contract exists by attempting to retrieve contract class
+        let response = match query {
+            Query::Class { class_hash } => {
+                let committed = state.committed();
+                let program = committed
+                    .declared_classes
+                    .get(&felt_to_hash(&class_hash))
+                    .or_else(|| committed.sierra_classes.get(&felt_to_hash(&class_hash)))
+                    .ok_or_else(|| anyhow!("Class {} is not declared", felt_to_hex(&class_hash)))?;
 
-                if state.get_contract_class(&class_hash).is_err() {
-                    bail!("No contract class found for contract address (Contract not deployed)");
+                QueryResponse::Class {
+                    program: program.clone(),
                 }
+            }
+            Query::ClassHashAt { address } => {
+                let class_hash = state
+                    .committed()
+                    .deployed_contracts
+                    .get(&Address(address.clone()))
+                    .ok_or_else(|| anyhow!("No contract deployed at {}", felt_to_hex(&address)))?;
+
+                QueryResponse::ClassHash {
+                    class_hash: felt_to_hex(&Felt252::from_bytes_be(class_hash)),
+                }
+            }
+            Query::StorageAt { address, key } => {
+                ensure!(
+                    state
+                        .committed()
+                        .deployed_contracts
+                        .contains_key(&Address(address.clone())),
+                    "No contract deployed at {}",
+                    felt_to_hex(&address)
+                );
+                let value = state
+                    .committed()
+                    .storage
+                    .get(&(Address(address), felt_to_hash(&key)))
+                    .cloned()
+                    .unwrap_or_else(Felt252::zero);
+
+                QueryResponse::Storage {
+                    value: felt_to_hex(&value),
+                }
+            }
+            Query::Nonce { address } => {
+                let nonce = self.next_nonce(state.committed(), &Address(address));
 
-                let entry_point = ExecutionEntryPoint::new(
-                    Address(contract_address.clone()),
+                QueryResponse::Nonce {
+                    nonce: felt_to_hex(&nonce),
+                }
+            }
+            Query::Receipt { transaction_hash } => {
+                let receipt = self.storage.receipt(&transaction_hash)?.ok_or_else(|| {
+                    anyhow!(
+                        "No receipt found for transaction {}",
+                        felt_to_hex(&transaction_hash)
+                    )
+                })?;
+
+                QueryResponse::Receipt(receipt)
+            }
+            Query::StateUpdate { block_number } => {
+                let state_update = self
+                    .storage
+                    .state_update(block_number)?
+                    .ok_or_else(|| anyhow!("No block committed at height {block_number}"))?;
+
+                QueryResponse::StateUpdate(state_update)
+            }
+            Query::Call {
+                address,
+                entry_point_selector,
+                calldata,
+            } => {
+                // calls run against their own copy of the committed state, so the lock is released before executing
+                let mut snapshot = state.snapshot();
+                drop(state);
+
+                let contract = felt_to_hex(&address);
+                let call_info = self
+                    .execute_entry_point(
+                        &mut snapshot,
+                        address,
+                        entry_point_selector,
+                        EntryPointType::External,
+                        calldata,
+                        &self.call_context(),
+                    )
+                    .map_err(|e| anyhow!("Call to {contract} failed: {e}"))?;
+                QueryResponse::Call {
+                    retdata: call_info.retdata.iter().map(felt_to_hex).collect(),
+                }
+            }
+        };
+
+        Ok(response)
+    }
+
+    /// Validations run before admitting a transaction into the mempool. Lookups are done against
+    /// the committed state, since that is what CometBFT rechecks the mempool against after each block.
+    /// Accounts validate their transactions against a copy of it.
+    fn validate_tx(&self, tx_bytes: &[u8]) -> Result<Transaction, (ErrorCode, String)> {
+        let tx: Transaction = bincode::deserialize(tx_bytes).map_err(|e| {
+            (
+                ErrorCode::MalformedTransaction,
+                format!("Could not decode transaction: {e}"),
+            )
+        })?;
+
+        match &tx.transaction_type {
+            TransactionType::Declare { program } => {
+                ContractClass::try_from(program.as_str()).map_err(|e| {
+                    (
+                        ErrorCode::InvalidContractClass,
+                        format!("Could not parse contract class: {e}"),
+                    )
+                })?;
+            }
+            TransactionType::DeclareV2 {
+                sierra_class,
+                casm_class,
+                compiled_class_hash,
+            } => {
+                SierraClass::from_json(sierra_class)
+                    .and_then(|_| check_compiled_class_hash(casm_class, compiled_class_hash))
+                    .map_err(|e| (ErrorCode::InvalidContractClass, e.to_string()))?;
+            }
+            _ => {}
+        }
+
+        tx.assert_integrity()
+            .map_err(|e| (ErrorCode::IntegrityCheckFailed, e.to_string()))?;
+
+        let state = self.starknet_state.lock().unwrap();
+        let sender_address = Address(tx.sender_address.clone());
+        let expected_nonce = self.next_nonce(state.committed(), &sender_address);
+        if tx.nonce != expected_nonce {
+            return Err((
+                ErrorCode::InvalidNonce,
+                format!(
+                    "Transaction nonce is {}, but the account expects {}",
+                    felt_to_hex(&tx.nonce),
+                    felt_to_hex(&expected_nonce)
+                ),
+            ));
+        }
+
+        match &tx.transaction_type {
+            TransactionType::DeployAccount { class_hash, .. } => {
+                if !state.committed().is_declared(&felt_to_hash(class_hash)) {
+                    return Err((
+                        ErrorCode::UnknownClassHash,
+                        format!("Class {} is not declared", felt_to_hex(class_hash)),
+                    ));
+                }
+
+                let mut snapshot = state.snapshot();
+                drop(state);
+                self.deploy_account(&mut snapshot, &tx)
+                    .and_then(|_| self.check_balance(&mut snapshot, &tx))
+                    .map_err(|e| (error_code(&e), format!("{e:#}")))?;
+            }
+            TransactionType::Declare { .. }
+            | TransactionType::DeclareV2 { .. }
+            | TransactionType::Invoke { .. } => {
+                let mut contract_addresses = vec![&tx.sender_address];
+                if let TransactionType::Invoke { address, .. } = &tx.transaction_type {
+                    contract_addresses.push(address);
+                }
+                for contract_address in contract_addresses {
+                    if !state
+                        .committed()
+                        .deployed_contracts
+                        .contains_key(&Address(contract_address.clone()))
+                    {
+                        return Err((
+                            ErrorCode::ContractNotDeployed,
+                            format!("No contract deployed at {}", felt_to_hex(contract_address)),
+                        ));
+                    }
+                }
+
+                // the account gets to reject the transaction before it reaches the mempool
+                let mut snapshot = state.snapshot();
+                drop(state);
+                self.check_class_is_supported(&mut snapshot, &tx)
+                    .and_then(|_| self.validate(&mut snapshot, &tx))
+                    .and_then(|_| self.check_balance(&mut snapshot, &tx))
+                    .map_err(|e| (error_code(&e), format!("{e:#}")))?;
+            }
+        }
+
+        // later transactions of the account have to come after this one
+        self.mempool_nonces
+            .lock()
+            .unwrap()
+            .insert(sender_address, tx.nonce.clone() + Felt252::from(1));
+
+        Ok(tx)
+    }
+
+    /// Nonce the next transaction of an account has to use: the one following its last transaction
+    /// in the mempool, or its committed nonce.
+    fn next_nonce(&self, committed: &StateDiff, address: &Address) -> Felt252 {
+        self.mempool_nonces
+            .lock()
+            .unwrap()
+            .get(address)
+            .or_else(|| committed.nonces.get(address))
+            .cloned()
+            .unwrap_or_else(Felt252::zero)
+    }
+
+    /// Make the writes of the delivered block part of the committed state, returning the resulting app hash.
+    /// Nothing is persisted until `persist` is called, so a node that stops in between executes the block
+    /// again on restart.
+    pub(crate) fn finalize(&self) -> [u8; 32] {
+        let height = self
+            .storage
+            .height()
+            .expect("Could not read last block height")
+            + 1;
+        let old_app_hash = self
+            .storage
+            .app_hash()
+            .expect("Could not read last app hash");
+
+        let receipts = std::mem::take(&mut *self.receipts.lock().unwrap());
+        *self.delivered_txs.lock().unwrap() = 0;
+
+        // the writes of executed transactions that were never delivered can't be committed
+        let optimistic = self.optimistic.lock().unwrap().take();
+        if let Some(results) = optimistic.filter(|results| !results.is_finished()) {
+            warn!(
+                "Delivered block ends before the executed one, after {} transactions",
+                receipts.len()
+            );
+            self.execute_delivered(&results);
+        }
+
+        let mut state = self.starknet_state.lock().unwrap();
+        let diff = state.commit();
+        let app_hash = state.commitment();
+        let state_update = state_update(height, &diff, &receipts, &old_app_hash, &app_hash);
+        info!(
+            "Finalized height {} with app hash {}",
+            height,
+            hex::encode(app_hash)
+        );
+
+        *self.finalized.lock().unwrap() = Some(FinalizedBlock {
+            height,
+            diff,
+            receipts,
+            state_update,
+            app_hash,
+        });
+        app_hash
+    }
+
+    /// Write the block computed by `finalize` to storage, if there is one.
+    pub(crate) fn persist(&self) {
+        let block = match self.finalized.lock().unwrap().take() {
+            Some(block) => block,
+            None => return,
+        };
+
+        info!("Committing height {}", block.height);
+        // if the block can't be persisted the node would diverge from its own storage on restart,
+        // so we crash intentionally
+        self.storage
+            .commit(
+                block.height,
+                &block.diff,
+                &block.receipts,
+                &block.state_update,
+                &block.app_hash,
+            )
+            .expect("Could not persist committed state");
+        self.metrics.commit(
+            block.height,
+            self.starknet_state.lock().unwrap().committed(),
+        );
+        self.mempool_nonces.lock().unwrap().clear();
+    }
+
+    /// Check the transactions of a proposed block by executing them in order against a copy of the committed state,
+    /// returning the results so they can be reused if the block is finalized.
+    /// Transactions of an account must use consecutive nonces, starting from the one the account is at,
+    /// and can't be rejected when executed, for example by the `__validate__` entry point of their sender.
+    fn check_proposal<T: AsRef<[u8]>>(&self, txs: &[T]) -> Result<ExecutedBlock> {
+        let mut state = self.starknet_state.lock().unwrap().snapshot();
+        let max_block_steps = self.max_block_steps();
+        let mut hashes = HashSet::new();
+        let mut total_steps = 0;
+        let mut results = Vec::new();
+
+        for (index, bytes) in txs.iter().enumerate() {
+            let tx: Transaction = bincode::deserialize(bytes.as_ref())
+                .map_err(|e| anyhow!("Transaction {index} can't be decoded: {e}"))?;
+            tx.assert_integrity()
+                .map_err(|e| anyhow!("Transaction {} is corrupted: {e}", tx.transaction_hash))?;
+            ensure!(
+                hashes.insert(tx.transaction_hash.clone()),
+                "Transaction {} is included more than once",
+                tx.transaction_hash
+            );
+
+            let expected_nonce = state
+                .get_nonce_at(&Address(tx.sender_address.clone()))?
+                .clone();
+            ensure!(
+                tx.nonce == expected_nonce,
+                "Transaction {} uses nonce {}, but its sender is at {}",
+                tx.transaction_hash,
+                felt_to_hex(&tx.nonce),
+                felt_to_hex(&expected_nonce)
+            );
+
+            // a rejected transaction doesn't use its nonce, so it can't be in a block; reverted ones can
+            let (code, outcome) = self
+                .execute_tx(&mut state, &tx)
+                .map_err(|e| anyhow!("Transaction {} is rejected: {e:#}", tx.transaction_hash))?;
+            total_steps += outcome.steps();
+            ensure!(
+                total_steps <= max_block_steps,
+                "Transactions take more than {max_block_steps} steps"
+            );
+            results.push((tx, code, outcome));
+        }
+
+        Ok(ExecutedBlock { state, results })
+    }
+
+    /// Code and outcome of a delivered transaction, taken from the execution of its block in `process_proposal`.
+    /// If the transaction is not the one executed at its position, the writes of that execution are discarded
+    /// and the transactions delivered so far are executed again, so the rest of the block runs normally.
+    fn optimistic_result(&self, tx: &Transaction) -> Option<(u32, ExecutionOutcome)> {
+        let mut optimistic = self.optimistic.lock().unwrap();
+        let mut results = optimistic.take()?;
+        if let Some(result) = results.next(tx) {
+            *optimistic = Some(results);
+            return Some(result);
+        }
+
+        warn!(
+            "Delivered block differs from the executed one at transaction {}",
+            tx.id
+        );
+        self.execute_delivered(&results);
+
+        None
+    }
+
+    /// Drop the writes of an executed block that the delivered one doesn't match, and execute again
+    /// the transactions delivered so far on top of the committed state.
+    fn execute_delivered(&self, results: &OptimisticResults) {
+        let mut state = self.starknet_state.lock().unwrap();
+        *state = state.snapshot();
+        for delivered in results.delivered() {
+            // the outcomes were already reported, and executing again yields the same ones
+            let _ = self.execute_tx(&mut state, delivered);
+        }
+    }
+
+    /// Cairo steps a transaction takes when executed on the given state, or `None` if it would be rejected.
+    /// Reverted transactions still use up the nonce of their sender, so they count as executed.
+    fn try_execute(&self, state: &mut StarknetState, tx: &Transaction) -> Option<usize> {
+        self.execute_tx(state, tx)
+            .ok()
+            .map(|(_, outcome)| outcome.steps())
+    }
+
+    /// Apply a transaction to the working state of a block, returning its code and outcome.
+    /// Proposals are executed speculatively too, so this only logs at the debug level.
+    /// An error means the transaction was rejected, in which case none of its writes are kept.
+    /// Invokes whose execution fails are not rejected: they use up their nonce, pay their fee
+    /// and are reported as reverted, with a non-zero code.
+    fn execute_tx(
+        &self,
+        state: &mut StarknetState,
+        tx: &Transaction,
+    ) -> Result<(u32, ExecutionOutcome)> {
+        match &tx.transaction_type {
+            TransactionType::Declare { .. } | TransactionType::DeclareV2 { .. } => {
+                let checkpoint = state.checkpoint();
+                let result = self
+                    .check_nonce(state, tx)
+                    .and_then(|_| self.validate(state, tx))
+                    .and_then(|call_info| {
+                        let class_hash = self.declare(state, tx)?;
+                        state.increment_nonce(&Address(tx.sender_address.clone()))?;
+                        let resources = call_resources(&call_info);
+                        let actual_fee = self.charge_fee(state, tx, &resources)?;
+                        Ok((class_hash, resources, actual_fee))
+                    });
+                if result.is_err() {
+                    state.rollback(checkpoint);
+                }
+                let (class_hash, execution_resources, actual_fee) = result?;
+
+                debug!(
+                    "Declared tx_id: {}, Sender: {}, Class Hash: {}",
+                    tx.id,
+                    felt_to_hex(&tx.sender_address),
+                    felt_to_hex(&class_hash)
+                );
+
+                Ok((
+                    0,
+                    ExecutionOutcome {
+                        class_hash: Some(felt_to_hex(&class_hash)),
+                        execution_resources,
+                        actual_fee,
+                        ..ExecutionOutcome::succeeded()
+                    },
+                ))
+            }
+            TransactionType::DeployAccount { .. } => {
+                let checkpoint = state.checkpoint();
+                let result = self
+                    .check_nonce(state, tx)
+                    .and_then(|_| self.deploy_account(state, tx))
+                    .and_then(|(address, resources)| {
+                        state.increment_nonce(&Address(address.clone()))?;
+                        let actual_fee = self.charge_fee(state, tx, &resources)?;
+                        Ok((address, resources, actual_fee))
+                    });
+                if result.is_err() {
+                    state.rollback(checkpoint);
+                }
+                let (address, execution_resources, actual_fee) = result?;
+
+                debug!(
+                    "Deployed tx_id {}, Address: {}, tx_hash: {}",
+                    tx.id, address, tx.transaction_hash
+                );
+
+                Ok((
+                    0,
+                    ExecutionOutcome {
+                        contract_address: Some(felt_to_hex(&address)),
+                        execution_resources,
+                        actual_fee,
+                        ..ExecutionOutcome::succeeded()
+                    },
+                ))
+            }
+            TransactionType::Invoke {
+                address,
+                entry_point_selector,
+                calldata,
+            } => {
+                let (code, outcome) = self.run_invoke_tx(state, tx)?;
+                debug!(
+                    "Invoked tx_id {}, Sender: {}, Address: {}, selector: {}, calldata: {:?}",
+                    tx.id,
+                    tx.sender_address,
+                    address,
+                    felt_to_hex(entry_point_selector),
                     calldata,
-                    Felt252::from_bytes_be(&starknet_rs::utils::calculate_sn_keccak(
-                        function.as_bytes(),
-                    )),
-                    Address(0.into()),
-                    EntryPointType::External,
-                    Some(CallType::Delegate),
-                    class_hash.into(),
                 );
+                debug!("Result: {:?}", outcome);
+
+                Ok((code, outcome))
+            }
+        }
+    }
 
-                let tx_execution_context = TransactionExecutionContext::create_for_testing(
-                    Address(0.into()),
-                    10,
-                    0.into(),
-                    self.config.invoke_tx_max_n_steps(),
-                    1,
+    /// Store the class of a declare transaction in the state, returning its class hash.
+    fn declare(&self, state: &mut StarknetState, tx: &Transaction) -> Result<Felt252> {
+        match &tx.transaction_type {
+            TransactionType::Declare { program } => {
+                let contract_class = ContractClass::try_from(program.as_str())
+                    .map_err(|e| anyhow!("Could not load contract from payload: {e}"))?;
+                // This function requires cairo_programs/contracts.json to exist as it uses that cairo program to compute the hash
+                let class_hash = compute_class_hash(&contract_class)?;
+                state.declare_class(felt_to_hash(&class_hash), program.clone(), contract_class);
+                Ok(class_hash)
+            }
+            TransactionType::DeclareV2 {
+                sierra_class,
+                casm_class,
+                compiled_class_hash,
+            } => {
+                check_compiled_class_hash(casm_class, compiled_class_hash)
+                    .map_err(|e| e.context(ErrorCode::InvalidContractClass))?;
+                let class_hash = SierraClass::from_json(sierra_class)?.compute_class_hash()?;
+                state.declare_sierra_class(
+                    felt_to_hash(&class_hash),
+                    sierra_class.clone(),
+                    compiled_class_hash.clone(),
                 );
+                Ok(class_hash)
+            }
+            _ => bail!("Not a declare transaction"),
+        }
+    }
 
-                let mut resources_manager = ExecutionResourcesManager::default();
+    /// Deploy the account of a deploy account transaction, run its constructor and let it validate
+    /// the transaction in `__validate_deploy__`. Returns the address of the account and the resources used.
+    fn deploy_account(
+        &self,
+        state: &mut StarknetState,
+        tx: &Transaction,
+    ) -> Result<(Felt252, ExecutionResources)> {
+        let TransactionType::DeployAccount {
+            class_hash,
+            constructor_calldata,
+            ..
+        } = &tx.transaction_type
+        else {
+            bail!("Not a deploy account transaction");
+        };
 
-                entry_point
-                    .execute(
-                        &mut state.clone(),
-                        &self.config,
-                        &mut resources_manager,
-                        &tx_execution_context,
-                    )
-                    .map_err(|_| anyhow!("Error running Invoke Tx"))
-            })
-            .unwrap();
+        self.check_class_is_supported(state, tx)?;
+        let address = tx.transaction_type.contract_address()?;
+        state.deploy_contract(Address(address.clone()), felt_to_hash(class_hash))?;
 
-        result
+        let tx_execution_context = self.execution_context(tx)?;
+        let mut resources =
+            self.run_constructor(state, &address, constructor_calldata, &tx_execution_context)?;
+
+        let call_info = self
+            .execute_entry_point(
+                state,
+                address.clone(),
+                selector(VALIDATE_DEPLOY_ENTRY_POINT),
+                EntryPointType::External,
+                tx.transaction_type.calldata()?,
+                &tx_execution_context,
+            )
+            .map_err(|e| e.context(ErrorCode::ValidationFailed))?;
+        resources.merge(&call_resources(&call_info));
+
+        Ok((address, resources))
+    }
+
+    /// Run the constructor of the contract deployed at `address`, if its class has one.
+    /// Classes without a constructor can only be deployed with empty calldata.
+    fn run_constructor(
+        &self,
+        state: &mut StarknetState,
+        address: &Felt252,
+        constructor_calldata: &[Felt252],
+        tx_execution_context: &TransactionExecutionContext,
+    ) -> Result<ExecutionResources> {
+        let class_hash = *state.get_class_hash_at(&Address(address.clone()))?;
+        let has_constructor = state
+            .get_contract_class(&class_hash)?
+            .entry_points_by_type()
+            .get(&EntryPointType::Constructor)
+            .is_some_and(|entry_points| !entry_points.is_empty());
+        if !has_constructor {
+            ensure!(
+                constructor_calldata.is_empty(),
+                "Class {} has no constructor, but constructor calldata was given",
+                felt_to_hex(&Felt252::from_bytes_be(&class_hash))
+            );
+            return Ok(ExecutionResources::default());
+        }
+
+        let call_info = self.execute_entry_point(
+            state,
+            address.clone(),
+            selector(CONSTRUCTOR_ENTRY_POINT),
+            EntryPointType::Constructor,
+            constructor_calldata.to_vec(),
+            tx_execution_context,
+        )?;
+        Ok(call_resources(&call_info))
     }
-}
 
-/// Local file used to track the last block height seen by the abci application.
-struct HeightFile;
+    /// Run an invoke through its sender account: `__validate__` first, then `__execute__`, which performs
+    /// the call, and finally charge the fee for both.
+    ///
+    /// An invoke the account doesn't validate, or whose fee can't be paid, is rejected and leaves no writes.
+    /// Once validated, the nonce is used up and the fee charged even if the execution fails or costs more
+    /// than the max fee: only the writes of `__execute__` are undone, and the transaction is reverted
+    /// with the code of its failure.
+    fn run_invoke_tx(
+        &self,
+        state: &mut StarknetState,
+        tx: &Transaction,
+    ) -> Result<(u32, ExecutionOutcome)> {
+        let calldata = tx.transaction_type.calldata()?;
+        let tx_execution_context = self.execution_context(tx)?;
 
-impl HeightFile {
-    const PATH: &str = "abci.height";
+        // writes are applied to the block's working state, and undone if the execution fails
+        let validated = state.checkpoint();
+        let validation = self
+            .check_nonce(state, tx)
+            .and_then(|_| self.check_class_is_supported(state, tx))
+            .and_then(|_| self.validate(state, tx))
+            .and_then(|call_info| {
+                state.increment_nonce(&Address(tx.sender_address.clone()))?;
+                Ok(call_info)
+            });
+        let validate_resources = match validation {
+            Ok(call_info) => call_resources(&call_info),
+            Err(error) => {
+                state.rollback(validated);
+                return Err(error);
+            }
+        };
 
-    fn read_or_create() -> i64 {
-        // if height file is missing or unreadable, create a new one from zero height
-        if let Ok(bytes) = std::fs::read(Self::PATH) {
-            // if contents are not readable, crash intentionally
-            bincode::deserialize(&bytes).expect("Contents of height file are not readable")
-        } else {
-            std::fs::write(Self::PATH, bincode::serialize(&0i64).unwrap()).unwrap();
-            0i64
+        let executed = state.checkpoint();
+        let execution = self.execute_entry_point(
+            state,
+            tx.sender_address.clone(),
+            selector(EXECUTE_ENTRY_POINT),
+            EntryPointType::External,
+            calldata,
+            &tx_execution_context,
+        );
+        // the resources the fee is charged for, which are the ones reported unless the execution failed
+        let (code, mut outcome, charged_resources) = match execution {
+            Ok(call_info) => {
+                let mut outcome = outcome_from_call_info(&call_info);
+                outcome.execution_resources.merge(&validate_resources);
+                let fee = self.calculate_fee(&outcome.execution_resources);
+                let charged_resources = outcome.execution_resources.clone();
+                if fee > tx.max_fee {
+                    state.rollback(executed);
+                    let reason = format!(
+                        "Transaction fee is {fee}, above its max fee of {}",
+                        tx.max_fee
+                    );
+                    let outcome = ExecutionOutcome {
+                        execution_resources: outcome.execution_resources,
+                        ..ExecutionOutcome::reverted(reason)
+                    };
+                    (ErrorCode::MaxFeeExceeded.into(), outcome, charged_resources)
+                } else {
+                    (0, outcome, charged_resources)
+                }
+            }
+            Err(error) => {
+                state.rollback(executed);
+                // the VM doesn't report how far a failed execution got, so only the resources of
+                // `__validate__` are reported and count toward the step budget of the block, while
+                // the fee is charged as if `__execute__` had taken all the steps it was allowed to
+                let mut charged_resources = ExecutionResources {
+                    n_steps: self.config.invoke_tx_max_n_steps() as usize,
+                    ..Default::default()
+                };
+                charged_resources.merge(&validate_resources);
+                let outcome = ExecutionOutcome {
+                    execution_resources: validate_resources,
+                    ..ExecutionOutcome::reverted(format!("{error:#}"))
+                };
+                (error_code(&error).into(), outcome, charged_resources)
+            }
+        };
+
+        // reverted transactions pay for what they used too, up to their max fee
+        let fee = self.calculate_fee(&charged_resources).min(tx.max_fee);
+        if let Err(error) = self.transfer_fee(state, tx, fee) {
+            state.rollback(validated);
+            return Err(error);
         }
+        outcome.actual_fee = fee;
+
+        Ok((code, outcome))
     }
 
-    fn increment() -> i64 {
-        // if the file is missing or contents are unexpected, we crash intentionally;
-        let mut height: i64 = bincode::deserialize(&std::fs::read(Self::PATH).unwrap()).unwrap();
-        height += 1;
-        std::fs::write(Self::PATH, bincode::serialize(&height).unwrap()).unwrap();
-        height
+    /// Fee for the given resources, in the smallest unit of the fee token.
+    fn calculate_fee(&self, resources: &ExecutionResources) -> u64 {
+        calculate_fee(resources, &self.fee_weights, self.fee_config().gas_price)
+    }
+
+    /// Transfer the fee for the given resources from the sender to the sequencer, returning it.
+    /// Fees above the max fee of the transaction are tagged with `ErrorCode::MaxFeeExceeded`.
+    fn charge_fee(
+        &self,
+        state: &mut StarknetState,
+        tx: &Transaction,
+        resources: &ExecutionResources,
+    ) -> Result<u64> {
+        let fee = self.calculate_fee(resources);
+        if fee > tx.max_fee {
+            return Err(anyhow!(
+                "Transaction fee is {fee}, above its max fee of {}",
+                tx.max_fee
+            )
+            .context(ErrorCode::MaxFeeExceeded));
+        }
+
+        self.transfer_fee(state, tx, fee)?;
+        Ok(fee)
+    }
+
+    /// Transfer a fee from the sender to the sequencer.
+    /// Errors are tagged with `ErrorCode::InsufficientBalance`.
+    fn transfer_fee(&self, state: &mut StarknetState, tx: &Transaction, fee: u64) -> Result<()> {
+        if fee == 0 {
+            return Ok(());
+        }
+
+        // the account pays the fee itself, so the transfer is called with the account as caller
+        let transfer = ExecutionEntryPoint::new(
+            Address(self.fee_token_address()?),
+            vec![
+                self.fee_config().sequencer_address.clone(),
+                // amount, as an Uint256
+                fee.into(),
+                0.into(),
+            ],
+            selector(TRANSFER_ENTRY_POINT),
+            Address(tx.sender_address.clone()),
+            EntryPointType::External,
+            Some(CallType::Call),
+            None,
+        );
+        transfer
+            .execute(
+                state,
+                &self.config,
+                &mut ExecutionResourcesManager::default(),
+                &self.execution_context(tx)?,
+            )
+            .map_err(|e| {
+                anyhow!("Could not charge fee: {e}").context(ErrorCode::InsufficientBalance)
+            })?;
+
+        Ok(())
+    }
+
+    /// Check that the sender holds enough of the fee token to pay the max fee of the transaction.
+    /// Errors are tagged with `ErrorCode::InsufficientBalance`.
+    fn check_balance(&self, state: &mut StarknetState, tx: &Transaction) -> Result<()> {
+        if self.fee_config().gas_price == 0 {
+            return Ok(());
+        }
+
+        let call_info = self.execute_entry_point(
+            state,
+            self.fee_token_address()?,
+            selector(BALANCE_OF_ENTRY_POINT),
+            EntryPointType::External,
+            vec![tx.sender_address.clone()],
+            &self.execution_context(tx)?,
+        )?;
+        let [low, high] = call_info.retdata.as_slice() else {
+            bail!(
+                "Unexpected balance returned by the fee token: {:?}",
+                call_info.retdata
+            );
+        };
+
+        if high.is_zero() && *low < Felt252::from(tx.max_fee) {
+            return Err(anyhow!(
+                "Balance of {} is {}, below the max fee of {}",
+                felt_to_hex(&tx.sender_address),
+                low,
+                tx.max_fee
+            )
+            .context(ErrorCode::InsufficientBalance));
+        }
+
+        Ok(())
+    }
+
+    fn fee_config(&self) -> FeeConfig {
+        self.chain_config.lock().unwrap().fee.clone()
+    }
+
+    fn max_block_steps(&self) -> usize {
+        self.chain_config.lock().unwrap().max_block_steps
+    }
+
+    fn fee_token_address(&self) -> Result<Felt252> {
+        self.fee_config()
+            .fee_token_address
+            .clone()
+            .ok_or_else(|| anyhow!("Fees are enabled but no fee token is configured"))
+    }
+
+    /// Check that a deploy account doesn't use, and an invoke doesn't call, a Cairo 1 class, since the
+    /// pinned starknet_in_rust can only execute Cairo 0 programs. Errors are tagged with `ErrorCode::UnsupportedClass`.
+    fn check_class_is_supported(&self, state: &mut StarknetState, tx: &Transaction) -> Result<()> {
+        let class_hash = match &tx.transaction_type {
+            TransactionType::DeployAccount { class_hash, .. } => felt_to_hash(class_hash),
+            TransactionType::Invoke { address, .. } => {
+                match state.get_class_hash_at(&Address(address.clone())) {
+                    Ok(class_hash) => *class_hash,
+                    // calls to addresses without a contract fail on their own
+                    Err(_) => return Ok(()),
+                }
+            }
+            TransactionType::Declare { .. } | TransactionType::DeclareV2 { .. } => return Ok(()),
+        };
+
+        if state.is_sierra_class(&class_hash) {
+            return Err(anyhow!(
+                "Class {} is a Cairo 1 class, which can't be executed yet",
+                felt_to_hex(&Felt252::from_bytes_be(&class_hash))
+            )
+            .context(ErrorCode::UnsupportedClass));
+        }
+
+        Ok(())
+    }
+
+    /// Check that the transaction uses the next nonce of its sender, as of the block being executed.
+    /// Errors are tagged with `ErrorCode::InvalidNonce`.
+    fn check_nonce(&self, state: &mut StarknetState, tx: &Transaction) -> Result<()> {
+        let nonce = state.get_nonce_at(&Address(tx.sender_address.clone()))?;
+        if *nonce != tx.nonce {
+            return Err(anyhow!(
+                "Transaction nonce is {}, but the account expects {}",
+                felt_to_hex(&tx.nonce),
+                felt_to_hex(nonce)
+            )
+            .context(ErrorCode::InvalidNonce));
+        }
+
+        Ok(())
+    }
+
+    /// Ask the sender account whether it accepts an invoke or a declare, which includes checking its
+    /// signature. Errors are tagged with `ErrorCode::ValidationFailed`.
+    fn validate(&self, state: &mut StarknetState, tx: &Transaction) -> Result<CallInfo> {
+        let entry_point = match tx.transaction_type {
+            TransactionType::Declare { .. } | TransactionType::DeclareV2 { .. } => {
+                VALIDATE_DECLARE_ENTRY_POINT
+            }
+            _ => VALIDATE_ENTRY_POINT,
+        };
+        self.execute_entry_point(
+            state,
+            tx.sender_address.clone(),
+            selector(entry_point),
+            EntryPointType::External,
+            tx.transaction_type.calldata()?,
+            &self.execution_context(tx)?,
+        )
+        .map_err(|e| e.context(ErrorCode::ValidationFailed))
+    }
+
+    /// Transaction info seen by the contracts executed on behalf of `tx`, which accounts use to check its signature.
+    fn execution_context(&self, tx: &Transaction) -> Result<TransactionExecutionContext> {
+        Ok(TransactionExecutionContext::new(
+            Address(tx.sender_address.clone()),
+            tx.hash()?,
+            tx.signature.clone(),
+            tx.max_fee,
+            tx.nonce.clone(),
+            self.config.invoke_tx_max_n_steps(),
+            tx.version,
+        ))
+    }
+
+    /// Transaction info for executions that are not part of a transaction, such as calls and genesis constructors.
+    fn call_context(&self) -> TransactionExecutionContext {
+        TransactionExecutionContext::create_for_testing(
+            Address(0.into()),
+            0,
+            0.into(),
+            self.config.invoke_tx_max_n_steps(),
+            TRANSACTION_VERSION,
+        )
+    }
+
+    fn execute_entry_point(
+        &self,
+        state: &mut StarknetState,
+        contract_address: Felt252,
+        entry_point_selector: Felt252,
+        entry_point_type: EntryPointType,
+        calldata: Vec<Felt252>,
+        tx_execution_context: &TransactionExecutionContext,
+    ) -> Result<CallInfo> {
+        let class_hash = *state
+            .get_class_hash_at(&Address(contract_address.clone()))
+            .map_err(|_| {
+                anyhow!(
+                    "No contract deployed at address {}",
+                    felt_to_hex(&contract_address)
+                )
+            })?;
+
+        // the pinned starknet_in_rust only runs Cairo 0 programs, so the CASM of Cairo 1 classes can't be run yet
+        if state.is_sierra_class(&class_hash) {
+            bail!(
+                "Contract at {} has a Cairo 1 class, whose entry points can't be executed yet",
+                felt_to_hex(&contract_address)
+            );
+        }
+
+        // check if contract exists by attempting to retrieve contract class
+
+        if state.get_contract_class(&class_hash).is_err() {
+            bail!("No contract class found for contract address (Contract not deployed)");
+        }
+
+        let selector = felt_to_hex(&entry_point_selector);
+        let entry_point = ExecutionEntryPoint::new(
+            Address(contract_address),
+            calldata,
+            entry_point_selector,
+            Address(0.into()),
+            entry_point_type,
+            Some(CallType::Delegate),
+            class_hash.into(),
+        );
+
+        let mut resources_manager = ExecutionResourcesManager::default();
+
+        entry_point
+            .execute(
+                state,
+                &self.config,
+                &mut resources_manager,
+                tx_execution_context,
+            )
+            .map_err(|e| anyhow!("Error running entry point {selector}: {e}"))
+    }
+}
+
+/// Record of the changes made by a block, with its roots being the app hashes before and after it.
+fn state_update(
+    height: i64,
+    diff: &StateDiff,
+    receipts: &[Receipt],
+    old_app_hash: &[u8],
+    app_hash: &[u8],
+) -> StateUpdate {
+    StateUpdate {
+        block_number: height,
+        new_root: felt_to_hex(&Felt252::from_bytes_be(app_hash)),
+        old_root: felt_to_hex(&Felt252::from_bytes_be(old_app_hash)),
+        transactions: receipts
+            .iter()
+            .map(|receipt| receipt.transaction_hash.clone())
+            .collect(),
+        state_diff: diff.to_block_diff(),
+    }
+}
+
+/// DeliverTx code and outcome of a transaction execution. Rejected transactions are reported as reverted.
+fn delivery_result(result: Result<(u32, ExecutionOutcome)>) -> (u32, ExecutionOutcome) {
+    match result {
+        Ok(delivery) => delivery,
+        Err(error) => (
+            error_code(&error).into(),
+            ExecutionOutcome::reverted(format!("{error:#}")),
+        ),
+    }
+}
+
+/// Check that the CASM sent with a declare v2 transaction is the one its compiled class hash commits to.
+fn check_compiled_class_hash(casm_class: &str, compiled_class_hash: &Felt252) -> Result<()> {
+    let casm_hash = CasmClass::from_json(casm_class)?.compute_compiled_class_hash()?;
+    ensure!(
+        casm_hash == *compiled_class_hash,
+        "Compiled class hash is {}, but the CASM sent hashes to {}",
+        felt_to_hex(compiled_class_hash),
+        felt_to_hex(&casm_hash)
+    );
+    Ok(())
+}
+
+/// Code reported for a failed transaction. Rejections by the sender account carry their own code.
+fn error_code(error: &anyhow::Error) -> ErrorCode {
+    error
+        .downcast_ref::<ErrorCode>()
+        .copied()
+        .unwrap_or(ErrorCode::ExecutionFailed)
+}
+
+/// Build the outcome of a successful execution out of its call tree.
+fn outcome_from_call_info(call_info: &CallInfo) -> ExecutionOutcome {
+    let mut events = Vec::new();
+    collect_events(call_info, &mut events);
+    events.sort_by_key(|(order, _)| *order);
+
+    ExecutionOutcome {
+        retdata: call_info.retdata.iter().map(felt_to_hex).collect(),
+        events: events.into_iter().map(|(_, event)| event).collect(),
+        execution_resources: call_resources(call_info),
+        ..ExecutionOutcome::succeeded()
+    }
+}
+
+/// Resources used by a call, including the ones used by its inner calls, which run on their own.
+fn call_resources(call_info: &CallInfo) -> ExecutionResources {
+    let own = &call_info.execution_resources;
+    let mut resources = ExecutionResources {
+        n_steps: own.n_steps,
+        n_memory_holes: own.n_memory_holes,
+        builtin_instance_counter: own.builtin_instance_counter.clone().into_iter().collect(),
+    };
+
+    for internal_call in call_info.internal_calls.iter() {
+        resources.merge(&call_resources(internal_call));
+    }
+    resources
+}
+
+/// Gather the events emitted by a call and all of its inner calls, along with their emission order.
+fn collect_events(call_info: &CallInfo, events: &mut Vec<(u64, execution::Event)>) {
+    for event in call_info.events.iter() {
+        events.push((
+            event.order,
+            execution::Event {
+                from_address: felt_to_hex(&call_info.contract_address.0),
+                keys: event.keys.iter().map(felt_to_hex).collect(),
+                data: event.data.iter().map(felt_to_hex).collect(),
+            },
+        ));
+    }
+
+    for internal_call in call_info.internal_calls.iter() {
+        collect_events(internal_call, events);
     }
 }
 
 // just covering a few special cases here. lower level test are done in record store and program store, higher level in integration tests.
 #[cfg(test)]
 mod tests {
-    fn _test_hook() {}
+    use super::*;
+    use assert_fs::TempDir;
+    use lib::parse_felt;
+    use retry::{delay::Fixed, retry};
+
+    /// Account that accepts every transaction.
+    const ACCOUNT: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../cairo_programs/account_without_validation.json"
+    );
+    /// Account that checks signatures, none of which are valid in these tests.
+    const SIGNING_ACCOUNT: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../cairo_programs/Account.json"
+    );
+
+    fn app(dir: &TempDir) -> StarknetApp {
+        let metrics = Metrics::new().unwrap();
+        StarknetApp::new(dir.path(), metrics).unwrap()
+    }
+
+    /// Application with accepting accounts deployed at `0x100` and `0x200`, and an account that
+    /// rejects every transaction at `0x300`.
+    fn app_with_accounts(dir: &TempDir) -> StarknetApp {
+        let app = app(dir);
+        let genesis = serde_json::json!({
+            "classes": {
+                "account": genesis_class(ACCOUNT),
+                "signing_account": genesis_class(SIGNING_ACCOUNT),
+            },
+            "contracts": [
+                { "address": "0x100", "class": "account" },
+                { "address": "0x200", "class": "account" },
+                { "address": "0x300", "class": "signing_account", "constructor_calldata": ["0x1234"] },
+            ],
+        });
+        app.init_chain(abci::RequestInitChain {
+            app_state_bytes: serde_json::to_vec(&genesis).unwrap().into(),
+            ..Default::default()
+        });
+        app
+    }
+
+    /// Genesis class at `path`, pinned to its hash.
+    fn genesis_class(path: &str) -> serde_json::Value {
+        let program = fs::read_to_string(path).unwrap();
+        let class_hash = compute_class_hash(&ContractClass::try_from(program.as_str()).unwrap());
+        serde_json::json!({ "path": path, "class_hash": felt_to_hex(&class_hash.unwrap()) })
+    }
+
+    /// Invoke of `assert_only_self` on `to` through the `sender` account, which only succeeds
+    /// when `to` is the sender itself.
+    fn invoke_to(sender: u64, nonce: u64, to: u64) -> Transaction {
+        let transaction_type = TransactionType::Invoke {
+            address: to.into(),
+            entry_point_selector: selector("assert_only_self"),
+            calldata: vec![],
+        };
+        Transaction::new(transaction_type, sender.into(), nonce.into(), 0).unwrap()
+    }
+
+    fn invoke(sender: u64, nonce: u64) -> Transaction {
+        invoke_to(sender, nonce, sender)
+    }
+
+    fn declare(sender: u64, nonce: u64) -> Transaction {
+        let program = fs::read_to_string(SIGNING_ACCOUNT).unwrap();
+        let transaction_type = TransactionType::Declare { program };
+        Transaction::new(transaction_type, sender.into(), nonce.into(), 0).unwrap()
+    }
+
+    /// Declare v2 of the Cairo 1 account from the compiler's test data, committing to `compiled_class_hash`.
+    fn declare_v2(sender: u64, nonce: u64, compiled_class_hash: Felt252) -> Transaction {
+        let class = |name| {
+            let path = format!(
+                "{}/../cairo_programs/cairo1/{name}",
+                env!("CARGO_MANIFEST_DIR")
+            );
+            fs::read_to_string(path).unwrap()
+        };
+        let transaction_type = TransactionType::DeclareV2 {
+            sierra_class: class("account.contract_class.json"),
+            casm_class: class("account.compiled_contract_class.json"),
+            compiled_class_hash,
+        };
+        Transaction::new(transaction_type, sender.into(), nonce.into(), 0).unwrap()
+    }
+
+    /// Deploy account of the class deployed at `deployed`, with the given constructor calldata.
+    fn deploy_account(
+        app: &StarknetApp,
+        deployed: u64,
+        constructor_calldata: Vec<Felt252>,
+    ) -> Transaction {
+        let class_hash = *app
+            .starknet_state
+            .lock()
+            .unwrap()
+            .get_class_hash_at(&Address(deployed.into()))
+            .unwrap();
+        let transaction_type = TransactionType::DeployAccount {
+            class_hash: Felt252::from_bytes_be(&class_hash),
+            salt: 0.into(),
+            constructor_calldata,
+        };
+        let sender_address = transaction_type.contract_address().unwrap();
+        Transaction::new(transaction_type, sender_address, 0.into(), 0).unwrap()
+    }
+
+    fn check(app: &StarknetApp, tx: &Transaction) -> u32 {
+        app.check_tx(abci::RequestCheckTx {
+            tx: bincode::serialize(tx).unwrap().into(),
+            ..Default::default()
+        })
+        .code
+    }
+
+    fn nonce(app: &StarknetApp, address: u64) -> Felt252 {
+        let mut state = app.starknet_state.lock().unwrap();
+        state
+            .get_nonce_at(&Address(address.into()))
+            .unwrap()
+            .clone()
+    }
+
+    fn deliver(app: &StarknetApp, tx: Vec<u8>) -> abci::ResponseDeliverTx {
+        app.deliver_tx(abci::RequestDeliverTx { tx: tx.into() })
+    }
+
+    fn encode<T: From<Vec<u8>>>(txs: &[Transaction]) -> Vec<T> {
+        txs.iter()
+            .map(|tx| bincode::serialize(tx).unwrap().into())
+            .collect()
+    }
+
+    /// Whether the proposal with the given hash and transactions is accepted.
+    fn process(app: &StarknetApp, hash: &[u8], txs: Vec<Vec<u8>>) -> bool {
+        let response = app.process_proposal(RequestProcessProposal {
+            txs: txs.into_iter().map(Into::into).collect(),
+            hash: hash.to_vec().into(),
+            ..Default::default()
+        });
+        response.status == response_process_proposal::ProposalStatus::Accept as i32
+    }
+
+    #[test]
+    fn undecodable_transactions_are_delivered_as_malformed() {
+        let dir = TempDir::new().unwrap();
+        let app = app(&dir);
+
+        let response = deliver(&app, vec![0xff; 3]);
+
+        assert_eq!(response.code, u32::from(ErrorCode::MalformedTransaction));
+        assert!(app.receipts.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn receipts_keep_the_position_of_their_transaction_in_the_block() {
+        let dir = TempDir::new().unwrap();
+        let app = app(&dir);
+        let mut corrupted = invoke(0x100, 0);
+        corrupted.transaction_hash = "0x1".to_string();
+
+        deliver(&app, vec![0xff]);
+        deliver(&app, bincode::serialize(&corrupted).unwrap());
+        deliver(&app, bincode::serialize(&invoke(0x100, 0)).unwrap());
+
+        let receipts = app.receipts.lock().unwrap();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].index, 2);
+    }
+
+    #[test]
+    fn declares_are_validated_by_their_sender_and_use_its_nonce() {
+        let dir = TempDir::new().unwrap();
+        let app = app_with_accounts(&dir);
+
+        assert_eq!(check(&app, &declare(0x100, 0)), 0);
+        assert_eq!(
+            check(&app, &declare(0x999, 0)),
+            u32::from(ErrorCode::ContractNotDeployed)
+        );
+        assert_eq!(
+            check(&app, &declare(0x300, 0)),
+            u32::from(ErrorCode::ValidationFailed)
+        );
+
+        let declared = deliver(&app, bincode::serialize(&declare(0x100, 0)).unwrap());
+        let replayed = deliver(&app, bincode::serialize(&declare(0x100, 0)).unwrap());
+
+        assert_eq!(declared.code, 0);
+        assert_eq!(replayed.code, u32::from(ErrorCode::InvalidNonce));
+        assert_eq!(nonce(&app, 0x100), Felt252::from(1));
+    }
+
+    #[test]
+    fn reverted_invokes_use_their_nonce_and_only_count_the_steps_that_were_measured() {
+        let dir = TempDir::new().unwrap();
+        let app = app_with_accounts(&dir);
+        let max_n_steps = app.config.invoke_tx_max_n_steps() as usize;
+
+        // 0x200 is not the sender, so the call fails in `__execute__`, after `__validate__` passed
+        let reverted = deliver(
+            &app,
+            bincode::serialize(&invoke_to(0x100, 0, 0x200)).unwrap(),
+        );
+        let next = deliver(&app, bincode::serialize(&invoke(0x100, 1)).unwrap());
+
+        assert_eq!(reverted.code, u32::from(ErrorCode::ExecutionFailed));
+        assert_eq!(next.code, 0);
+        assert_eq!(nonce(&app, 0x100), Felt252::from(2));
+
+        let outcome = ExecutionOutcome::decode(&reverted.data).unwrap();
+        assert!(!outcome.is_success());
+        assert!(outcome.steps() < max_n_steps);
+        app.commit();
+
+        // the steps a failed `__execute__` was allowed don't fill up the block
+        app.chain_config.lock().unwrap().max_block_steps = max_n_steps;
+        let reverted: Vec<_> = (2..5).map(|nonce| invoke_to(0x100, nonce, 0x200)).collect();
+        assert!(process(&app, b"reverted", encode(&reverted)));
+    }
+
+    #[test]
+    fn declares_v2_must_send_the_casm_their_compiled_class_hash_commits_to() {
+        let dir = TempDir::new().unwrap();
+        let app = app_with_accounts(&dir);
+        let compiled_class_hash =
+            parse_felt("0x4b552d087e9633fbecf2185d144fafca55e6581502c0fc93953c143757dc8bf")
+                .unwrap();
+
+        assert_eq!(
+            check(&app, &declare_v2(0x100, 0, compiled_class_hash.clone())),
+            0
+        );
+        assert_eq!(
+            check(&app, &declare_v2(0x100, 0, Felt252::from(1))),
+            u32::from(ErrorCode::InvalidContractClass)
+        );
+
+        let rejected = deliver(
+            &app,
+            bincode::serialize(&declare_v2(0x100, 0, Felt252::from(1))).unwrap(),
+        );
+        let declared = deliver(
+            &app,
+            bincode::serialize(&declare_v2(0x100, 0, compiled_class_hash)).unwrap(),
+        );
+
+        assert_eq!(rejected.code, u32::from(ErrorCode::InvalidContractClass));
+        assert_eq!(declared.code, 0);
+        assert_eq!(nonce(&app, 0x100), Felt252::from(1));
+    }
+
+    #[test]
+    fn cairo_1_classes_can_be_declared_but_not_deployed_or_invoked() {
+        let dir = TempDir::new().unwrap();
+        let app = app_with_accounts(&dir);
+        let compiled_class_hash =
+            parse_felt("0x4b552d087e9633fbecf2185d144fafca55e6581502c0fc93953c143757dc8bf")
+                .unwrap();
+        let declared = deliver(
+            &app,
+            bincode::serialize(&declare_v2(0x100, 0, compiled_class_hash)).unwrap(),
+        );
+        let outcome = ExecutionOutcome::decode(&declared.data).unwrap();
+        let class_hash = parse_felt(&outcome.class_hash.unwrap()).unwrap();
+        // a contract of the class can only get there through the state itself
+        app.starknet_state
+            .lock()
+            .unwrap()
+            .deploy_contract(Address(0x400.into()), felt_to_hash(&class_hash))
+            .unwrap();
+        app.commit();
+
+        let transaction_type = TransactionType::DeployAccount {
+            class_hash,
+            salt: 0.into(),
+            constructor_calldata: vec![],
+        };
+        let sender_address = transaction_type.contract_address().unwrap();
+        let deploy = Transaction::new(transaction_type, sender_address, 0.into(), 0).unwrap();
+        let unsupported = u32::from(ErrorCode::UnsupportedClass);
+
+        assert_eq!(check(&app, &deploy), unsupported);
+        assert_eq!(check(&app, &invoke_to(0x100, 1, 0x400)), unsupported);
+        assert_eq!(
+            deliver(&app, bincode::serialize(&deploy).unwrap()).code,
+            unsupported
+        );
+        assert_eq!(
+            deliver(
+                &app,
+                bincode::serialize(&invoke_to(0x200, 0, 0x400)).unwrap()
+            )
+            .code,
+            unsupported
+        );
+        assert_eq!(nonce(&app, 0x200), Felt252::zero());
+    }
+
+    #[test]
+    fn storage_is_only_read_at_deployed_contracts() {
+        let dir = TempDir::new().unwrap();
+        let app = app_with_accounts(&dir);
+        let storage_at = |address: u64| {
+            app.run_query(Query::StorageAt {
+                address: address.into(),
+                key: 1.into(),
+            })
+        };
+
+        assert!(matches!(
+            storage_at(0x100).unwrap(),
+            QueryResponse::Storage { value } if value == "0x0"
+        ));
+        assert!(storage_at(0x999).is_err());
+    }
+
+    #[test]
+    fn proposals_leave_out_used_nonces_but_keep_the_transactions_after_them() {
+        let dir = TempDir::new().unwrap();
+        let app = app_with_accounts(&dir);
+        deliver(&app, bincode::serialize(&invoke(0x100, 0)).unwrap());
+        app.commit();
+
+        let mempool = [
+            invoke(0x100, 0),
+            invoke(0x100, 1),
+            invoke(0x100, 2),
+            // skips nonce 0, so neither transaction of 0x200 can be included
+            invoke(0x200, 1),
+            invoke(0x200, 2),
+        ];
+        let txs = encode(&mempool);
+        let proposal = app.prepare_proposal(RequestPrepareProposal {
+            txs: txs.clone(),
+            max_tx_bytes: i64::MAX,
+            ..Default::default()
+        });
+
+        assert_eq!(proposal.txs, txs[1..3]);
+    }
+
+    #[test]
+    fn proposals_are_rejected_unless_they_could_have_been_prepared() {
+        let dir = TempDir::new().unwrap();
+        let app = app_with_accounts(&dir);
+
+        // reverted transactions use their nonce, so they can be included
+        assert!(process(
+            &app,
+            b"valid",
+            encode(&[
+                invoke(0x100, 0),
+                invoke_to(0x100, 1, 0x200),
+                invoke(0x200, 0)
+            ])
+        ));
+        // the account at 0x300 rejects every transaction in `__validate__`
+        assert!(!process(&app, b"rejected", encode(&[invoke(0x300, 0)])));
+        assert!(!process(&app, b"gap", encode(&[invoke(0x100, 1)])));
+        assert!(!process(
+            &app,
+            b"repeated",
+            encode(&[invoke(0x100, 0), invoke(0x100, 0)])
+        ));
+        assert!(!process(&app, b"undecodable", vec![vec![0xff]]));
+    }
+
+    #[test]
+    fn proposals_leave_out_rejected_transactions_and_fit_the_step_budget() {
+        let dir = TempDir::new().unwrap();
+        let app = app_with_accounts(&dir);
+        let steps = {
+            let mut state = app.starknet_state.lock().unwrap().snapshot();
+            app.try_execute(&mut state, &invoke(0x100, 0)).unwrap()
+        };
+        app.chain_config.lock().unwrap().max_block_steps = steps;
+
+        let txs = encode(&[invoke(0x300, 0), invoke(0x100, 0), invoke(0x200, 0)]);
+        let proposal = app.prepare_proposal(RequestPrepareProposal {
+            txs: txs.clone(),
+            max_tx_bytes: i64::MAX,
+            ..Default::default()
+        });
+
+        assert_eq!(proposal.txs, txs[1..2]);
+        assert!(!process(
+            &app,
+            b"over budget",
+            encode(&[invoke(0x100, 0), invoke(0x200, 0)])
+        ));
+    }
+
+    #[test]
+    fn finalized_proposals_are_delivered_with_the_results_of_their_execution() {
+        let dir = TempDir::new().unwrap();
+        let app = app_with_accounts(&dir);
+        let block = [invoke(0x100, 0), invoke_to(0x100, 1, 0x200)];
+        assert!(process(&app, b"block", encode(&block)));
+
+        app.begin_block(abci::RequestBeginBlock {
+            hash: b"block".to_vec().into(),
+            ..Default::default()
+        });
+        let codes: Vec<_> = encode(&block)
+            .into_iter()
+            .map(|tx| deliver(&app, tx).code)
+            .collect();
+
+        assert_eq!(codes, [0, u32::from(ErrorCode::ExecutionFailed)]);
+        assert!(app.optimistic.lock().unwrap().is_some());
+        assert_eq!(app.receipts.lock().unwrap().len(), 2);
+        assert_eq!(nonce(&app, 0x100), Felt252::from(2));
+    }
+
+    /// Finalize the block with the given hash and transactions, returning the codes they were delivered
+    /// with and the resulting app hash.
+    fn finalize(app: &StarknetApp, hash: &[u8], txs: &[Transaction]) -> (Vec<u32>, Vec<u8>) {
+        app.begin_block(abci::RequestBeginBlock {
+            hash: hash.to_vec().into(),
+            ..Default::default()
+        });
+        let codes = encode(txs)
+            .into_iter()
+            .map(|tx| deliver(app, tx).code)
+            .collect();
+        app.end_block(Default::default());
+        (codes, app.commit().data.to_vec())
+    }
+
+    #[test]
+    fn finalized_proposals_adopt_the_state_they_were_executed_into() {
+        let (executed_dir, delivered_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let (executed, delivered) = (
+            app_with_accounts(&executed_dir),
+            app_with_accounts(&delivered_dir),
+        );
+        let block = [
+            invoke(0x100, 0),
+            invoke_to(0x100, 1, 0x200),
+            invoke(0x200, 0),
+        ];
+
+        assert!(process(&executed, b"block", encode(&block)));
+        assert!(process(&executed, b"other", encode(&block[..1])));
+        executed.begin_block(abci::RequestBeginBlock {
+            hash: b"block".to_vec().into(),
+            ..Default::default()
+        });
+
+        // the writes of the block are there before any of its transactions is delivered
+        assert_eq!(nonce(&executed, 0x100), Felt252::from(2));
+        assert!(executed.proposals.lock().unwrap().is_empty());
+
+        let codes: Vec<_> = encode(&block)
+            .into_iter()
+            .map(|tx| deliver(&executed, tx).code)
+            .collect();
+        executed.end_block(Default::default());
+        let app_hash = executed.commit().data.to_vec();
+
+        assert_eq!((codes, app_hash), finalize(&delivered, b"block", &block));
+        let metrics = String::from_utf8(executed.metrics.encode().unwrap()).unwrap();
+        assert!(metrics.contains("sequencer_proposal_execution_seconds_count 2"));
+        assert!(metrics.contains(
+            r#"sequencer_block_execution_seconds_count{executed_in="process_proposal"} 1"#
+        ));
+        assert!(metrics.contains("sequencer_deliver_tx_seconds_count 0"));
+    }
+
+    #[test]
+    fn blocks_that_end_before_their_proposal_only_commit_what_was_delivered() {
+        let (executed_dir, delivered_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let (executed, delivered) = (
+            app_with_accounts(&executed_dir),
+            app_with_accounts(&delivered_dir),
+        );
+        assert!(process(
+            &executed,
+            b"block",
+            encode(&[invoke(0x100, 0), invoke(0x100, 1)])
+        ));
+
+        let block = [invoke(0x100, 0)];
+        let (codes, app_hash) = finalize(&executed, b"block", &block);
+
+        assert_eq!(codes, [0]);
+        assert_eq!((codes, app_hash), finalize(&delivered, b"block", &block));
+        assert_eq!(nonce(&executed, 0x100), Felt252::from(1));
+    }
+
+    #[test]
+    fn constructors_run_whenever_the_class_has_one() {
+        let dir = TempDir::new().unwrap();
+        let app = app_with_accounts(&dir);
+
+        assert_eq!(check(&app, &deploy_account(&app, 0x100, vec![])), 0);
+        // the class at 0x100 has no constructor to take the calldata
+        assert_ne!(check(&app, &deploy_account(&app, 0x100, vec![1.into()])), 0);
+        // the constructor of the class at 0x300 runs, and fails without the public key it takes
+        assert_ne!(check(&app, &deploy_account(&app, 0x300, vec![])), 0);
+    }
+
+    #[test]
+    fn genesis_classes_must_match_their_hash_and_contracts_their_constructor() {
+        let load = |genesis: serde_json::Value| {
+            let dir = TempDir::new().unwrap();
+            let genesis = Genesis::from_bytes(&serde_json::to_vec(&genesis).unwrap()).unwrap();
+            app(&dir).load_genesis(genesis)
+        };
+        let signing_account = genesis_class(SIGNING_ACCOUNT);
+
+        let error = load(serde_json::json!({
+            "classes": { "account": { "path": ACCOUNT, "class_hash": signing_account["class_hash"] } },
+        }))
+        .unwrap_err();
+        assert!(error.to_string().contains("but the genesis expects"));
+        // the constructor runs, and fails without the public key it takes
+        assert!(load(serde_json::json!({
+            "classes": { "account": signing_account },
+            "contracts": [{ "address": "0x100", "class": "account" }],
+        }))
+        .is_err());
+        // a class without a constructor takes no calldata
+        assert!(load(serde_json::json!({
+            "classes": { "account": genesis_class(ACCOUNT) },
+            "contracts": [{ "address": "0x100", "class": "account", "constructor_calldata": ["0x1"] }],
+        }))
+        .is_err());
+    }
+
+    #[test]
+    fn chain_parameters_are_read_from_the_genesis_and_kept_on_restart() {
+        let dir = TempDir::new().unwrap();
+        let app = app(&dir);
+        let genesis = serde_json::json!({
+            "fee_token": { "address": "0x200", "gas_price": 5, "sequencer_address": "0x100" },
+            "max_block_steps": 1000,
+        });
+        app.init_chain(abci::RequestInitChain {
+            app_state_bytes: serde_json::to_vec(&genesis).unwrap().into(),
+            ..Default::default()
+        });
+        // sled releases its lock on the database from a background thread once it is dropped
+        drop(app);
+
+        let app = retry(Fixed::from_millis(100).take(50), || {
+            StarknetApp::new(dir.path(), Metrics::new().unwrap())
+        })
+        .unwrap();
+        assert_eq!(
+            app.fee_config(),
+            FeeConfig {
+                gas_price: 5,
+                fee_token_address: Some(0x200.into()),
+                sequencer_address: 0x100.into(),
+            }
+        );
+        assert_eq!(app.max_block_steps(), 1000);
+    }
 }
@@ -0,0 +1,318 @@
+use crate::state::StateDiff;
+use once_cell::sync::Lazy;
+use starknet_crypto::{pedersen_hash, poseidon_hash, FieldElement};
+use starknet_rs::utils::{felt_to_hash, Address};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Height of Starknet's Patricia-Merkle trees, keys are 251-bit field elements.
+const TREE_HEIGHT: usize = 251;
+
+/// Version prefix of the leaves of Cairo 1 classes in the class tree.
+static CONTRACT_CLASS_LEAF_VERSION: Lazy<FieldElement> = Lazy::new(|| {
+    FieldElement::from_byte_slice_be(b"CONTRACT_CLASS_LEAF_V0").expect("Invalid short string")
+});
+
+/// Commitment over the whole Starknet state, following Starknet's state commitment scheme:
+/// - each contract's storage is committed in a Patricia-Merkle tree keyed by storage address,
+/// - each contract state is `h(h(h(class_hash, storage_root), nonce), 0)`,
+/// - contract states are committed in a Patricia-Merkle tree keyed by contract address.
+///
+/// Declared classes are committed in a second tree keyed by class hash, and both roots are
+/// hashed together so that declaring a class also changes the commitment. Cairo 1 class leaves are
+/// `poseidon("CONTRACT_CLASS_LEAF_V0", compiled_class_hash)`.
+///
+/// Addresses with storage or a nonce but no deployed contract are committed with a zero class hash,
+/// so every write of a block changes the commitment.
+///
+/// The trees are kept between blocks and updated with the writes of each one, so committing a block
+/// only rehashes the paths of the entries it touched.
+#[derive(Clone, Debug)]
+pub struct StateCommitment {
+    contracts: MerkleTree,
+    classes: MerkleTree,
+    storage: HashMap<[u8; 32], MerkleTree>,
+    root: FieldElement,
+}
+
+impl Default for StateCommitment {
+    fn default() -> Self {
+        Self {
+            contracts: MerkleTree::default(),
+            classes: MerkleTree::default(),
+            storage: HashMap::new(),
+            root: pedersen_hash(&FieldElement::ZERO, &FieldElement::ZERO),
+        }
+    }
+}
+
+impl StateCommitment {
+    /// Build the commitment of a whole state.
+    pub fn new(state: &StateDiff) -> Self {
+        let mut commitment = Self::default();
+        commitment.update(state, state);
+        commitment
+    }
+
+    /// Apply the writes of a block, where `state` is the committed state they were merged into.
+    pub fn update(&mut self, state: &StateDiff, diff: &StateDiff) {
+        for ((address, key), value) in diff.storage.iter() {
+            self.storage
+                .entry(felt_to_hash(&address.0))
+                .or_default()
+                .set(*key, to_field_element(&felt_to_hash(value)));
+        }
+
+        let addresses: HashSet<&Address> = diff
+            .deployed_contracts
+            .keys()
+            .chain(diff.nonces.keys())
+            .chain(diff.storage.keys().map(|(address, _)| address))
+            .collect();
+        for address in addresses {
+            let class_hash = state
+                .deployed_contracts
+                .get(address)
+                .map(to_field_element)
+                .unwrap_or(FieldElement::ZERO);
+            let nonce = state
+                .nonces
+                .get(address)
+                .map(|nonce| to_field_element(&felt_to_hash(nonce)))
+                .unwrap_or(FieldElement::ZERO);
+            let address = felt_to_hash(&address.0);
+            let storage_root = self
+                .storage
+                .get_mut(&address)
+                .map(MerkleTree::root)
+                .unwrap_or(FieldElement::ZERO);
+
+            // as in Starknet, a contract state without class, storage or nonce is an empty leaf
+            let contract_state = if class_hash == FieldElement::ZERO
+                && storage_root == FieldElement::ZERO
+                && nonce == FieldElement::ZERO
+            {
+                FieldElement::ZERO
+            } else {
+                pedersen_hash(
+                    &pedersen_hash(&pedersen_hash(&class_hash, &storage_root), &nonce),
+                    &FieldElement::ZERO,
+                )
+            };
+            self.contracts.set(address, contract_state);
+        }
+
+        for class_hash in diff.declared_classes.keys() {
+            self.classes.set(*class_hash, to_field_element(class_hash));
+        }
+        // Cairo 1 classes commit to their compiled class hash, as in Starknet's class tree
+        for (class_hash, compiled_class_hash) in diff.compiled_class_hashes.iter() {
+            let leaf = poseidon_hash(
+                *CONTRACT_CLASS_LEAF_VERSION,
+                to_field_element(&felt_to_hash(compiled_class_hash)),
+            );
+            self.classes.set(*class_hash, leaf);
+        }
+
+        self.root = pedersen_hash(&self.contracts.root(), &self.classes.root());
+    }
+
+    pub fn root(&self) -> FieldElement {
+        self.root
+    }
+}
+
+/// Patricia-Merkle tree that remembers the hash of its nodes, so changing a leaf only rehashes the
+/// nodes on its path. Zero-valued leaves are treated as empty, and keys are below 2^251 as in Starknet.
+#[derive(Clone, Debug, Default)]
+struct MerkleTree {
+    // keys are big endian, so the leaves of every subtree are a contiguous range
+    leaves: BTreeMap<[u8; 32], FieldElement>,
+    /// Hashes computed so far, by the depth of the node and the path leading to it.
+    nodes: HashMap<(usize, [u8; 32]), FieldElement>,
+}
+
+impl MerkleTree {
+    fn set(&mut self, key: [u8; 32], value: FieldElement) {
+        if value == FieldElement::ZERO {
+            self.leaves.remove(&key);
+        } else {
+            self.leaves.insert(key, value);
+        }
+
+        for depth in 0..=TREE_HEIGHT {
+            self.nodes.remove(&(depth, mask(&key, depth, false)));
+        }
+    }
+
+    fn root(&mut self) -> FieldElement {
+        if self.leaves.is_empty() {
+            return FieldElement::ZERO;
+        }
+        self.node_hash(0, [0; 32])
+    }
+
+    /// Hash of the node at `depth` whose leaves start with `prefix`, which must have some.
+    fn node_hash(&mut self, depth: usize, prefix: [u8; 32]) -> FieldElement {
+        if let Some(hash) = self.nodes.get(&(depth, prefix)) {
+            return *hash;
+        }
+
+        let mut leaves = self.leaves.range(prefix..=mask(&prefix, depth, true));
+        let (first, value) = leaves.next().map(|(key, value)| (*key, *value)).unwrap();
+        let last = leaves.next_back().map(|(key, _)| *key).unwrap_or(first);
+
+        // the node is an edge down to where its first and last leaves part ways, or to the leaf itself
+        let hash = match (depth..TREE_HEIGHT).find(|&i| bit(&first, i) != bit(&last, i)) {
+            None => edge(value, &first, depth, TREE_HEIGHT - depth),
+            Some(branch) => {
+                let left = self.node_hash(branch + 1, mask(&first, branch + 1, false));
+                let right = self.node_hash(branch + 1, mask(&last, branch + 1, false));
+                edge(pedersen_hash(&left, &right), &first, depth, branch - depth)
+            }
+        };
+
+        self.nodes.insert((depth, prefix), hash);
+        hash
+    }
+}
+
+/// Hash of a node at `depth` reaching `child` through an edge of `length` bits of `key`.
+fn edge(child: FieldElement, key: &[u8; 32], depth: usize, length: usize) -> FieldElement {
+    if length == 0 {
+        return child;
+    }
+    pedersen_hash(&child, &path(key, depth, length)) + FieldElement::from(length as u64)
+}
+
+/// `key` with every bit below `depth` cleared, or set if `fill` is true. Clearing them gives the
+/// first key under the node at `depth` on the path to `key`, and setting them gives the last one.
+fn mask(key: &[u8; 32], depth: usize, fill: bool) -> [u8; 32] {
+    let mut key = *key;
+    let bits = TREE_HEIGHT - depth;
+    let (bytes, rest) = (bits / 8, bits % 8);
+    for byte in key[32 - bytes..].iter_mut() {
+        *byte = if fill { 0xff } else { 0 };
+    }
+    if rest > 0 {
+        let low = (1u8 << rest) - 1;
+        let byte = &mut key[31 - bytes];
+        *byte = if fill { *byte | low } else { *byte & !low };
+    }
+    key
+}
+
+/// Bits `depth..depth + length` of `key`, as a number.
+fn path(key: &[u8; 32], depth: usize, length: usize) -> FieldElement {
+    let mut bytes = [0u8; 32];
+    for i in 0..length {
+        if bit(key, depth + i) {
+            let index = length - 1 - i;
+            bytes[31 - index / 8] |= 1 << (index % 8);
+        }
+    }
+    to_field_element(&bytes)
+}
+
+/// Bit of `key` found at `depth` when walking the tree from the root (most significant bit first).
+fn bit(key: &[u8; 32], depth: usize) -> bool {
+    let index = TREE_HEIGHT - 1 - depth;
+    key[31 - index / 8] & (1 << (index % 8)) != 0
+}
+
+fn to_field_element(bytes: &[u8; 32]) -> FieldElement {
+    // felts and class hashes are always below the field modulus
+    FieldElement::from_bytes_be(bytes).expect("Value out of field range")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use felt::Felt252;
+
+    fn merkle_root(leaves: Vec<([u8; 32], FieldElement)>) -> FieldElement {
+        let mut tree = MerkleTree::default();
+        for (key, value) in leaves {
+            tree.set(key, value);
+        }
+        tree.root()
+    }
+
+    fn key(n: u8) -> [u8; 32] {
+        let mut key = [0u8; 32];
+        key[31] = n;
+        key
+    }
+
+    #[test]
+    fn single_leaf_is_an_edge_from_the_root() {
+        let value = FieldElement::from(7u64);
+        let expected =
+            pedersen_hash(&value, &FieldElement::from(5u64)) + FieldElement::from(251u64);
+
+        assert_eq!(merkle_root(vec![(key(5), value)]), expected);
+    }
+
+    #[test]
+    fn root_does_not_depend_on_insertion_order() {
+        let leaves = vec![
+            (key(1), FieldElement::from(10u64)),
+            (key(2), FieldElement::from(20u64)),
+            (key(200), FieldElement::from(30u64)),
+        ];
+        let mut reversed = leaves.clone();
+        reversed.reverse();
+
+        assert_eq!(merkle_root(leaves), merkle_root(reversed));
+    }
+
+    #[test]
+    fn zero_leaves_are_empty() {
+        let leaf = (key(1), FieldElement::from(10u64));
+
+        assert_eq!(
+            merkle_root(vec![leaf, (key(2), FieldElement::ZERO)]),
+            merkle_root(vec![leaf])
+        );
+        assert_eq!(merkle_root(vec![]), FieldElement::ZERO);
+    }
+
+    #[test]
+    fn updated_tree_matches_a_tree_built_from_scratch() {
+        let mut tree = MerkleTree::default();
+        for n in [1, 2, 3, 200] {
+            tree.set(key(n), FieldElement::from(n as u64));
+        }
+        tree.root();
+
+        tree.set(key(2), FieldElement::from(50u64));
+        tree.set(key(3), FieldElement::ZERO);
+        tree.set(key(4), FieldElement::from(60u64));
+
+        let expected = merkle_root(vec![
+            (key(1), FieldElement::from(1u64)),
+            (key(2), FieldElement::from(50u64)),
+            (key(4), FieldElement::from(60u64)),
+            (key(200), FieldElement::from(200u64)),
+        ]);
+        assert_eq!(tree.root(), expected);
+    }
+
+    #[test]
+    fn storage_and_nonces_of_addresses_without_contracts_are_committed() {
+        let address = Address(Felt252::from(0x100));
+        let empty = StateCommitment::new(&StateDiff::default()).root();
+
+        let mut storage = StateDiff::default();
+        storage
+            .storage
+            .insert((address.clone(), key(1)), Felt252::from(5));
+        let mut nonces = StateDiff::default();
+        nonces.nonces.insert(address, Felt252::from(1));
+
+        let storage = StateCommitment::new(&storage).root();
+        let nonces = StateCommitment::new(&nonces).root();
+        assert_ne!(storage, empty);
+        assert_ne!(nonces, empty);
+        assert_ne!(storage, nonces);
+    }
+}
@@ -0,0 +1,228 @@
+use anyhow::Result;
+use felt::Felt252;
+use lib::fee::FeeConfig;
+use lib::{parse_felt, serde_felt};
+use num_traits::Zero;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Storage variable where OpenZeppelin's ERC-20 keeps balances, as an `Uint256` per account.
+pub const ERC20_BALANCES_STORAGE_VAR: &str = "ERC20_balances";
+
+/// Cairo steps the transactions of a block can take at most, unless the genesis sets otherwise.
+const DEFAULT_MAX_BLOCK_STEPS: usize = 50_000_000;
+
+/// Initial Starknet state of the chain, read from the `app_state` of CometBFT's genesis file.
+/// Felts are written as `0x`-prefixed hex or decimal strings.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Genesis {
+    /// Classes to declare, by name.
+    #[serde(default)]
+    pub classes: BTreeMap<String, GenesisClass>,
+    /// Contracts to deploy, in order.
+    #[serde(default)]
+    pub contracts: Vec<GenesisContract>,
+    /// Storage values to set once the contracts are deployed.
+    #[serde(default)]
+    pub storage: Vec<GenesisStorage>,
+    #[serde(default)]
+    pub fee_token: Option<GenesisFeeToken>,
+    /// Cairo steps the transactions of a block can take at most.
+    #[serde(default = "default_max_block_steps")]
+    pub max_block_steps: usize,
+}
+
+/// Compiled class read from the node's disk. Its hash is part of the genesis, so every node
+/// declares the same class whatever file it has at that path.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GenesisClass {
+    /// Path to the compiled JSON, relative to the directory the application runs from.
+    pub path: PathBuf,
+    /// Hash the class at `path` must have.
+    #[serde(deserialize_with = "serde_felt::lenient::deserialize")]
+    pub class_hash: Felt252,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GenesisContract {
+    #[serde(deserialize_with = "serde_felt::lenient::deserialize")]
+    pub address: Felt252,
+    /// Name of the contract class, among the genesis classes.
+    pub class: String,
+    #[serde(default, deserialize_with = "serde_felt::lenient::vec::deserialize")]
+    pub constructor_calldata: Vec<Felt252>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GenesisStorage {
+    #[serde(deserialize_with = "serde_felt::lenient::deserialize")]
+    pub address: Felt252,
+    #[serde(deserialize_with = "serde_felt::lenient::deserialize")]
+    pub key: Felt252,
+    #[serde(deserialize_with = "serde_felt::lenient::deserialize")]
+    pub value: Felt252,
+}
+
+/// Token fees are paid with, its initial balances and the price of gas in it.
+/// The token contract itself is deployed like any other genesis contract.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GenesisFeeToken {
+    #[serde(deserialize_with = "serde_felt::lenient::deserialize")]
+    pub address: Felt252,
+    /// Amount held by each account, in the smallest unit of the token.
+    #[serde(default)]
+    pub balances: BTreeMap<String, String>,
+    /// Price of a unit of L1 gas, in the smallest unit of the token. Fees are not charged if zero.
+    #[serde(default)]
+    pub gas_price: u64,
+    /// Address receiving the fees.
+    #[serde(
+        default = "Felt252::zero",
+        deserialize_with = "serde_felt::lenient::deserialize"
+    )]
+    pub sequencer_address: Felt252,
+}
+
+/// Parameters of the chain that change the results of executing a block, so every validator has to use
+/// the same ones. They are set by the genesis and persisted along with the committed state.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainConfig {
+    pub fee: FeeConfig,
+    /// Cairo steps the transactions of a block can take at most.
+    pub max_block_steps: usize,
+}
+
+impl Default for ChainConfig {
+    fn default() -> Self {
+        Self {
+            fee: FeeConfig::default(),
+            max_block_steps: DEFAULT_MAX_BLOCK_STEPS,
+        }
+    }
+}
+
+fn default_max_block_steps() -> usize {
+    DEFAULT_MAX_BLOCK_STEPS
+}
+
+impl Genesis {
+    /// Parse the genesis app state. An empty app state means the chain starts with an empty state.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let bytes = if bytes.iter().all(u8::is_ascii_whitespace) {
+            b"{}"
+        } else {
+            bytes
+        };
+
+        Ok(serde_json::from_slice(bytes)?)
+    }
+
+    /// Chain parameters set by the genesis.
+    pub fn chain_config(&self) -> ChainConfig {
+        let fee = self
+            .fee_token
+            .as_ref()
+            .map(|fee_token| FeeConfig {
+                gas_price: fee_token.gas_price,
+                fee_token_address: Some(fee_token.address.clone()),
+                sequencer_address: fee_token.sequencer_address.clone(),
+            })
+            .unwrap_or_default();
+
+        ChainConfig {
+            fee,
+            max_block_steps: self.max_block_steps,
+        }
+    }
+}
+
+impl GenesisFeeToken {
+    /// Balances as account addresses and amounts.
+    pub fn balances(&self) -> Result<Vec<(Felt252, u128)>> {
+        self.balances
+            .iter()
+            .map(|(account, amount)| Ok((parse_felt(account)?, amount.parse()?)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_app_state_is_an_empty_genesis() {
+        let genesis = Genesis::from_bytes(b"").unwrap();
+
+        assert!(genesis.classes.is_empty());
+        assert!(genesis.contracts.is_empty());
+        assert!(genesis.fee_token.is_none());
+        assert_eq!(genesis.chain_config(), ChainConfig::default());
+    }
+
+    #[test]
+    fn genesis_is_parsed() {
+        let genesis = Genesis::from_bytes(
+            br#"{
+                "classes": {
+                    "account": { "path": "cairo_programs/Account.json", "class_hash": "0xabc" }
+                },
+                "contracts": [
+                    { "address": "0x100", "class": "account", "constructor_calldata": ["0x1234"] }
+                ],
+                "storage": [{ "address": "0x100", "key": "5", "value": "0xff" }],
+                "fee_token": {
+                    "address": "0x200",
+                    "balances": { "0x100": "1000000" },
+                    "gas_price": 100,
+                    "sequencer_address": "0x300"
+                },
+                "max_block_steps": 1000
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(genesis.classes["account"].class_hash, Felt252::from(0xabc));
+        assert_eq!(genesis.contracts[0].address, Felt252::from(0x100));
+        assert_eq!(
+            genesis.contracts[0].constructor_calldata,
+            vec![Felt252::from(0x1234)]
+        );
+        assert_eq!(genesis.storage[0].key, Felt252::from(5));
+        assert_eq!(
+            genesis.chain_config(),
+            ChainConfig {
+                fee: FeeConfig {
+                    gas_price: 100,
+                    fee_token_address: Some(Felt252::from(0x200)),
+                    sequencer_address: Felt252::from(0x300),
+                },
+                max_block_steps: 1000,
+            }
+        );
+        assert_eq!(
+            genesis.fee_token.unwrap().balances().unwrap(),
+            vec![(Felt252::from(0x100), 1_000_000)]
+        );
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        assert!(Genesis::from_bytes(br#"{ "accounts": [] }"#).is_err());
+    }
+
+    #[test]
+    fn classes_must_pin_their_hash() {
+        let genesis = br#"{ "classes": { "account": "cairo_programs/Account.json" } }"#;
+        assert!(Genesis::from_bytes(genesis).is_err());
+
+        let genesis = br#"{ "classes": { "account": { "path": "cairo_programs/Account.json" } } }"#;
+        assert!(Genesis::from_bytes(genesis).is_err());
+    }
+}
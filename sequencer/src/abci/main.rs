@@ -1,9 +1,20 @@
 use application::StarknetApp;
 use clap::Parser;
+use metrics::Metrics;
+use std::path::PathBuf;
+#[cfg(not(feature = "abci2"))]
 use tendermint_abci::ServerBuilder;
 use tracing_subscriber::{filter::LevelFilter, util::SubscriberInitExt};
 
+#[cfg(feature = "abci2")]
+mod abci2;
 mod application;
+mod commitment;
+mod genesis;
+mod metrics;
+mod proposal;
+mod state;
+mod storage;
 
 #[derive(Debug, Parser)]
 #[clap(author, version, about)]
@@ -21,6 +32,18 @@ struct Cli {
     #[clap(short, long, default_value = "1048576")]
     read_buf_size: usize,
 
+    /// Serve Prometheus metrics on this host. Use 0.0.0.0 to let other machines or containers scrape them.
+    #[clap(long, default_value = "127.0.0.1")]
+    metrics_host: String,
+
+    /// Serve Prometheus metrics on this port.
+    #[clap(long, default_value = "26670")]
+    metrics_port: u16,
+
+    /// Directory where the application state is persisted.
+    #[clap(long, default_value = "abci.db")]
+    data_dir: PathBuf,
+
     /// Increase output logging verbosity to DEBUG level.
     #[clap(short, long)]
     verbose: bool,
@@ -53,10 +76,26 @@ fn main() {
 
     subscriber.init();
 
-    let app = StarknetApp::new();
-    let server = ServerBuilder::new(cli.read_buf_size)
-        .bind(format!("{}:{}", cli.host, cli.port), app)
-        .unwrap();
+    let metrics = Metrics::new().expect("Could not register metrics");
+    metrics
+        .serve(
+            format!("{}:{}", cli.metrics_host, cli.metrics_port)
+                .parse()
+                .expect("Invalid metrics address"),
+        )
+        .expect("Could not serve metrics");
+
+    let app = StarknetApp::new(&cli.data_dir, metrics).expect("Could not load application state");
+    let address = format!("{}:{}", cli.host, cli.port);
 
-    server.listen().unwrap();
+    // CometBFT 0.38 speaks ABCI 2.0, while Tendermint 0.34 and CometBFT 0.37 use the server of tendermint-abci
+    #[cfg(feature = "abci2")]
+    abci2::serve(&address, app, cli.read_buf_size).unwrap();
+
+    #[cfg(not(feature = "abci2"))]
+    ServerBuilder::new(cli.read_buf_size)
+        .bind(address, app)
+        .unwrap()
+        .listen()
+        .unwrap();
 }
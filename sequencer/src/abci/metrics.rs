@@ -0,0 +1,302 @@
+use crate::state::StateDiff;
+use anyhow::Result;
+use axum::extract::State;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Router, Server};
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::runtime;
+use tower_http::timeout::TimeoutLayer;
+use tracing::{info, warn};
+
+/// Time scrapers have to send the headers of a request, so idle connections don't pile up.
+const HEADER_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time a request has to be read and answered.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Prometheus metrics of the application, shared by every clone of `StarknetApp`.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    registry: Registry,
+    block_transactions: HistogramVec,
+    failed_transactions: IntCounterVec,
+    deliver_tx_seconds: Histogram,
+    cairo_steps: IntCounter,
+    block_execution_seconds: HistogramVec,
+    proposal_execution_seconds: Histogram,
+    block_height: IntGauge,
+    state_size: IntGaugeVec,
+    /// Figures of the block being executed.
+    block: Arc<Mutex<BlockMetrics>>,
+}
+
+/// Transactions delivered so far on the current block, by type, when the block started and
+/// where it was executed.
+#[derive(Debug, Default)]
+struct BlockMetrics {
+    started: Option<Instant>,
+    executed_in: &'static str,
+    transactions: HashMap<&'static str, usize>,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let block_transactions = HistogramVec::new(
+            HistogramOpts::new(
+                "sequencer_block_transactions",
+                "Transactions delivered per block, by transaction type",
+            )
+            .buckets(exponential_buckets(1.0, 2.0, 14)?),
+            &["type"],
+        )?;
+        let failed_transactions = IntCounterVec::new(
+            Opts::new(
+                "sequencer_failed_transactions_total",
+                "Delivered transactions that failed or were reverted, by transaction type",
+            ),
+            &["type"],
+        )?;
+        let deliver_tx_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "sequencer_deliver_tx_seconds",
+                "Time taken to execute a delivered transaction",
+            )
+            .buckets(exponential_buckets(0.0005, 2.0, 14)?),
+        )?;
+        let cairo_steps = IntCounter::new(
+            "sequencer_cairo_steps_total",
+            "Cairo steps executed by delivered transactions",
+        )?;
+        let block_execution_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "sequencer_block_execution_seconds",
+                "Time between the start and the end of a block, by the hook that executed it",
+            )
+            .buckets(exponential_buckets(0.01, 2.0, 14)?),
+            &["executed_in"],
+        )?;
+        let proposal_execution_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "sequencer_proposal_execution_seconds",
+                "Time taken to check and execute a proposed block in process_proposal",
+            )
+            .buckets(exponential_buckets(0.01, 2.0, 14)?),
+        )?;
+        let block_height = IntGauge::new("sequencer_block_height", "Last committed height")?;
+        let state_size = IntGaugeVec::new(
+            Opts::new(
+                "sequencer_state_size",
+                "Entries in the committed Starknet state, by kind",
+            ),
+            &["kind"],
+        )?;
+
+        let registry = Registry::new();
+        registry.register(Box::new(block_transactions.clone()))?;
+        registry.register(Box::new(failed_transactions.clone()))?;
+        registry.register(Box::new(deliver_tx_seconds.clone()))?;
+        registry.register(Box::new(cairo_steps.clone()))?;
+        registry.register(Box::new(block_execution_seconds.clone()))?;
+        registry.register(Box::new(proposal_execution_seconds.clone()))?;
+        registry.register(Box::new(block_height.clone()))?;
+        registry.register(Box::new(state_size.clone()))?;
+
+        Ok(Self {
+            registry,
+            block_transactions,
+            failed_transactions,
+            deliver_tx_seconds,
+            cairo_steps,
+            block_execution_seconds,
+            proposal_execution_seconds,
+            block_height,
+            state_size,
+            block: Arc::new(Mutex::new(BlockMetrics::default())),
+        })
+    }
+
+    /// Start timing a block, whose transactions were already executed if it was `proposed`.
+    pub fn begin_block(&self, proposed: bool) {
+        let mut block = self.block.lock().unwrap();
+        block.started = Some(Instant::now());
+        block.executed_in = if proposed {
+            "process_proposal"
+        } else {
+            "deliver_tx"
+        };
+        block.transactions.clear();
+    }
+
+    /// Record the time taken to execute a proposed block.
+    pub fn process_proposal(&self, started: Instant) {
+        self.proposal_execution_seconds
+            .observe(started.elapsed().as_secs_f64());
+    }
+
+    /// Record a delivered transaction of the given type, with how long it took and the Cairo steps it ran.
+    /// `started` is `None` for transactions whose execution in `process_proposal` was reused, so they
+    /// don't count toward the `deliver_tx` latency.
+    pub fn deliver_tx(
+        &self,
+        transaction_type: &'static str,
+        started: Option<Instant>,
+        steps: usize,
+        failed: bool,
+    ) {
+        if let Some(started) = started {
+            self.deliver_tx_seconds
+                .observe(started.elapsed().as_secs_f64());
+        }
+        self.cairo_steps.inc_by(steps as u64);
+        if failed {
+            self.failed_transactions
+                .with_label_values(&[transaction_type])
+                .inc();
+        }
+
+        *self
+            .block
+            .lock()
+            .unwrap()
+            .transactions
+            .entry(transaction_type)
+            .or_default() += 1;
+    }
+
+    /// Record the transactions and execution time of the block, returning them to be logged.
+    pub fn end_block(&self) -> (usize, u128) {
+        let block = self.block.lock().unwrap();
+        for transaction_type in lib::TransactionType::NAMES {
+            let count = block
+                .transactions
+                .get(transaction_type)
+                .copied()
+                .unwrap_or(0);
+            self.block_transactions
+                .with_label_values(&[transaction_type])
+                .observe(count as f64);
+        }
+
+        let elapsed = block
+            .started
+            .map(|started| started.elapsed())
+            .unwrap_or_default();
+        self.block_execution_seconds
+            .with_label_values(&[block.executed_in])
+            .observe(elapsed.as_secs_f64());
+
+        (block.transactions.values().sum(), elapsed.as_millis())
+    }
+
+    pub fn commit(&self, height: i64, committed: &StateDiff) {
+        self.block_height.set(height);
+
+        let classes = committed.declared_classes.len() + committed.sierra_classes.len();
+        for (kind, size) in [
+            ("classes", classes),
+            ("contracts", committed.deployed_contracts.len()),
+            ("storage_slots", committed.storage.len()),
+        ] {
+            self.state_size.with_label_values(&[kind]).set(size as i64);
+        }
+    }
+
+    /// Metrics in the Prometheus text format.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(buffer)
+    }
+
+    /// Serve the metrics over HTTP under `/metrics`, on a background thread.
+    pub fn serve(&self, address: SocketAddr) -> Result<()> {
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let server = {
+            let _context = runtime.enter();
+            Server::try_bind(&address)?
+                .http1_header_read_timeout(HEADER_READ_TIMEOUT)
+                .serve(self.router().into_make_service())
+        };
+        info!("Serving metrics on http://{address}/metrics");
+
+        thread::spawn(move || {
+            if let Err(e) = runtime.block_on(server) {
+                warn!("Error serving metrics: {e}");
+            }
+        });
+
+        Ok(())
+    }
+
+    fn router(&self) -> Router {
+        Router::new()
+            .route("/metrics", get(respond))
+            .with_state(self.clone())
+            .layer(TimeoutLayer::new(REQUEST_TIMEOUT))
+    }
+}
+
+async fn respond(State(metrics): State<Metrics>) -> Response {
+    match metrics.encode() {
+        Ok(body) => (
+            [(header::CONTENT_TYPE, TextEncoder::new().format_type())],
+            body,
+        )
+            .into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    #[test]
+    fn block_figures_are_exported() {
+        let metrics = Metrics::new().unwrap();
+        metrics.begin_block(true);
+        metrics.deliver_tx("invoke", Some(Instant::now()), 100, false);
+        metrics.deliver_tx("invoke", None, 50, true);
+        assert_eq!(metrics.end_block().0, 2);
+
+        let output = String::from_utf8(metrics.encode().unwrap()).unwrap();
+        assert!(output.contains("sequencer_cairo_steps_total 150"));
+        assert!(output.contains(r#"sequencer_failed_transactions_total{type="invoke"} 1"#));
+        assert!(output.contains(r#"sequencer_block_transactions_sum{type="invoke"} 2"#));
+        assert!(output.contains("sequencer_deliver_tx_seconds_count 1"));
+        assert!(output.contains(
+            r#"sequencer_block_execution_seconds_count{executed_in="process_proposal"} 1"#
+        ));
+    }
+
+    #[tokio::test]
+    async fn metrics_are_served_under_their_path() {
+        let metrics = Metrics::new().unwrap();
+        let request = |uri| Request::get(uri).body(Body::empty()).unwrap();
+
+        let served = metrics.router().oneshot(request("/metrics")).await.unwrap();
+        let elsewhere = metrics.router().oneshot(request("/")).await.unwrap();
+
+        assert_eq!(served.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(served.into_body()).await.unwrap();
+        assert!(String::from_utf8(body.to_vec())
+            .unwrap()
+            .contains("sequencer_block_height 0"));
+        assert_eq!(elsewhere.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,238 @@
+use crate::state::StarknetState;
+use felt::Felt252;
+use lib::execution::ExecutionOutcome;
+use lib::Transaction;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+
+/// A mempool transaction considered for inclusion in a block proposal.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub bytes: Vec<u8>,
+    pub transaction: Transaction,
+    /// Position of the transaction in the mempool, which breaks ties between equal fees.
+    position: usize,
+}
+
+/// Decode the transactions of the mempool, leaving out those that can't be decoded, fail their
+/// integrity check or repeat the hash of an earlier one.
+pub fn decode_candidates<T: AsRef<[u8]>>(txs: &[T]) -> Vec<Candidate> {
+    let mut hashes = HashSet::new();
+
+    txs.iter()
+        .enumerate()
+        .filter_map(|(position, bytes)| {
+            let transaction: Transaction = bincode::deserialize(bytes.as_ref()).ok()?;
+            if transaction.assert_integrity().is_err()
+                || !hashes.insert(transaction.transaction_hash.clone())
+            {
+                return None;
+            }
+
+            Some(Candidate {
+                bytes: bytes.as_ref().to_vec(),
+                transaction,
+                position,
+            })
+        })
+        .collect()
+}
+
+/// Candidates in the order they are proposed: the transactions of each sender go in nonce order,
+/// and among the next transaction of every sender the one with the highest max fee goes first.
+#[derive(Debug, Default)]
+pub struct ProposalQueue {
+    queues: Vec<VecDeque<Candidate>>,
+    senders: HashMap<Felt252, usize>,
+    /// Max fee and mempool position of the first transaction of each queue that has one.
+    heads: BinaryHeap<(u64, Reverse<usize>, usize)>,
+}
+
+impl ProposalQueue {
+    pub fn new(candidates: Vec<Candidate>) -> Self {
+        let mut queue = Self::default();
+        let mut by_sender: Vec<Vec<Candidate>> = Vec::new();
+
+        for candidate in candidates {
+            let sender = candidate.transaction.sender_address.clone();
+            let index = *queue.senders.entry(sender).or_insert_with(|| {
+                by_sender.push(Vec::new());
+                by_sender.len() - 1
+            });
+            by_sender[index].push(candidate);
+        }
+
+        for mut transactions in by_sender {
+            // of several transactions using the same nonce, only the one paying the most can be included
+            transactions.sort_by_key(|candidate| {
+                (
+                    candidate.transaction.nonce.clone(),
+                    Reverse(candidate.transaction.max_fee),
+                    candidate.position,
+                )
+            });
+            transactions.dedup_by(|next, first| next.transaction.nonce == first.transaction.nonce);
+
+            queue.queues.push(transactions.into());
+            queue.push_head(queue.queues.len() - 1);
+        }
+
+        queue
+    }
+
+    /// Next transaction to try to include in the proposal.
+    pub fn pop(&mut self) -> Option<Candidate> {
+        while let Some((_, _, index)) = self.heads.pop() {
+            // the queue might have been emptied by `skip_sender` since its head was pushed
+            if let Some(candidate) = self.queues[index].pop_front() {
+                self.push_head(index);
+                return Some(candidate);
+            }
+        }
+
+        None
+    }
+
+    /// Leave out the remaining transactions of the sender of a transaction that didn't make it
+    /// into the proposal, since their nonces can't be used until it is included.
+    pub fn skip_sender(&mut self, candidate: &Candidate) {
+        if let Some(index) = self.senders.get(&candidate.transaction.sender_address) {
+            self.queues[*index].clear();
+        }
+    }
+
+    fn push_head(&mut self, index: usize) {
+        if let Some(head) = self.queues[index].front() {
+            self.heads
+                .push((head.transaction.max_fee, Reverse(head.position), index));
+        }
+    }
+}
+
+/// A proposed block executed in `process_proposal`, kept in case it gets finalized.
+#[derive(Debug)]
+pub struct ExecutedBlock {
+    /// Working state after executing the block, with its writes still pending.
+    pub state: StarknetState,
+    /// Every transaction of the block with the DeliverTx code and outcome of its execution.
+    pub results: Vec<(Transaction, u32, ExecutionOutcome)>,
+}
+
+/// Results of an executed block that got finalized, handed out as its transactions are delivered.
+#[derive(Debug)]
+pub struct OptimisticResults {
+    results: Vec<(Transaction, u32, ExecutionOutcome)>,
+    delivered: usize,
+}
+
+impl OptimisticResults {
+    pub fn new(results: Vec<(Transaction, u32, ExecutionOutcome)>) -> Self {
+        Self {
+            results,
+            delivered: 0,
+        }
+    }
+
+    /// Code and outcome of a delivered transaction, if it's the one that was executed at its position.
+    pub fn next(&mut self, transaction: &Transaction) -> Option<(u32, ExecutionOutcome)> {
+        let (executed, code, outcome) = self.results.get(self.delivered)?;
+        if executed.transaction_hash != transaction.transaction_hash {
+            return None;
+        }
+
+        self.delivered += 1;
+        Some((*code, outcome.clone()))
+    }
+
+    /// Transactions delivered so far.
+    pub fn delivered(&self) -> impl Iterator<Item = &Transaction> {
+        self.results[..self.delivered].iter().map(|(tx, _, _)| tx)
+    }
+
+    /// Whether every executed transaction was delivered.
+    pub fn is_finished(&self) -> bool {
+        self.delivered == self.results.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib::TransactionType;
+
+    fn candidate(sender: u64, nonce: u64, max_fee: u64, position: usize) -> Candidate {
+        let transaction = Transaction {
+            transaction_type: TransactionType::DeployAccount {
+                class_hash: 1.into(),
+                salt: 0.into(),
+                constructor_calldata: vec![],
+            },
+            transaction_hash: format!("0x{position:x}"),
+            id: position.to_string(),
+            sender_address: sender.into(),
+            nonce: nonce.into(),
+            max_fee,
+            version: 1,
+            signature: vec![],
+        };
+
+        Candidate {
+            bytes: vec![],
+            transaction,
+            position,
+        }
+    }
+
+    fn order(queue: &mut ProposalQueue) -> Vec<usize> {
+        std::iter::from_fn(|| queue.pop())
+            .map(|candidate| candidate.position)
+            .collect()
+    }
+
+    #[test]
+    fn senders_go_in_nonce_order_and_by_fee_between_them() {
+        let mut queue = ProposalQueue::new(vec![
+            candidate(0xa, 1, 50, 0),
+            candidate(0xa, 0, 10, 1),
+            candidate(0xb, 0, 20, 2),
+            candidate(0xb, 1, 5, 3),
+            // pays less than the other transaction with the same nonce
+            candidate(0xb, 0, 1, 4),
+        ]);
+
+        assert_eq!(order(&mut queue), [2, 1, 0, 3]);
+    }
+
+    #[test]
+    fn skipped_senders_have_their_later_transactions_left_out() {
+        let mut queue = ProposalQueue::new(vec![
+            candidate(0xa, 0, 10, 0),
+            candidate(0xa, 1, 10, 1),
+            candidate(0xb, 0, 5, 2),
+        ]);
+
+        let first = queue.pop().unwrap();
+        queue.skip_sender(&first);
+        assert_eq!(order(&mut queue), [2]);
+    }
+
+    #[test]
+    fn optimistic_results_are_only_handed_out_in_block_order() {
+        let (first, second) = (candidate(0xa, 0, 0, 0), candidate(0xa, 1, 0, 1));
+        let mut results = OptimisticResults::new(vec![
+            (first.transaction.clone(), 0, ExecutionOutcome::succeeded()),
+            (second.transaction.clone(), 1, ExecutionOutcome::succeeded()),
+        ]);
+
+        assert!(results.next(&second.transaction).is_none());
+        assert_eq!(
+            results.next(&first.transaction).map(|(code, _)| code),
+            Some(0)
+        );
+        assert_eq!(results.delivered().count(), 1);
+        assert_eq!(
+            results.next(&second.transaction).map(|(code, _)| code),
+            Some(1)
+        );
+    }
+}
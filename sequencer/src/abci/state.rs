@@ -0,0 +1,466 @@
+use crate::commitment::StateCommitment;
+use anyhow::Result;
+use felt::Felt252;
+use lib::block;
+use lib::felt_to_hex;
+use num_traits::Zero;
+use once_cell::sync::Lazy;
+use starknet_rs::business_logic::state::state_api::{State, StateReader};
+use starknet_rs::business_logic::state::state_cache::StorageEntry;
+use starknet_rs::core::errors::state_errors::StateError;
+use starknet_rs::services::api::contract_class::ContractClass;
+use starknet_rs::utils::{Address, ClassHash};
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::Arc;
+
+/// Value returned for storage slots and nonces that were never written.
+static ZERO: Lazy<Felt252> = Lazy::new(Felt252::zero);
+
+/// A set of writes to the Starknet state.
+/// It is used both for the changes of the block being executed and, accumulated, for the whole committed state.
+#[derive(Clone, Debug, Default)]
+pub struct StateDiff {
+    /// Declared classes, kept as their JSON source so they can be persisted and parsed back on restart.
+    pub declared_classes: HashMap<ClassHash, String>,
+    /// Declared Cairo 1 classes, kept as their Sierra JSON.
+    pub sierra_classes: HashMap<ClassHash, String>,
+    /// Hash of the CASM each Cairo 1 class compiles to.
+    pub compiled_class_hashes: HashMap<ClassHash, Felt252>,
+    pub deployed_contracts: HashMap<Address, ClassHash>,
+    pub nonces: HashMap<Address, Felt252>,
+    pub storage: HashMap<StorageEntry, Felt252>,
+}
+
+impl StateDiff {
+    /// Whether a Cairo 0 or Cairo 1 class has been declared under the hash.
+    pub fn is_declared(&self, class_hash: &ClassHash) -> bool {
+        self.declared_classes.contains_key(class_hash)
+            || self.sierra_classes.contains_key(class_hash)
+    }
+
+    /// Writes in the form reported by the `StateUpdate` of a block.
+    pub fn to_block_diff(&self) -> block::StateDiff {
+        let hex = |bytes: &[u8; 32]| felt_to_hex(&Felt252::from_bytes_be(bytes));
+
+        let mut storage: BTreeMap<Felt252, Vec<(Felt252, &Felt252)>> = BTreeMap::new();
+        for ((address, key), value) in self.storage.iter() {
+            storage
+                .entry(address.0.clone())
+                .or_default()
+                .push((Felt252::from_bytes_be(key), value));
+        }
+        let storage_diffs = storage
+            .into_iter()
+            .map(|(address, mut entries)| {
+                entries.sort();
+                block::StorageDiff {
+                    address: felt_to_hex(&address),
+                    storage_entries: entries
+                        .into_iter()
+                        .map(|(key, value)| block::StorageEntry {
+                            key: felt_to_hex(&key),
+                            value: felt_to_hex(value),
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+
+        let mut deprecated_declared_classes: Vec<_> = self.declared_classes.keys().collect();
+        deprecated_declared_classes.sort();
+        let mut declared_classes: Vec<_> = self.compiled_class_hashes.iter().collect();
+        declared_classes.sort();
+        let mut deployed_contracts: Vec<_> = self
+            .deployed_contracts
+            .iter()
+            .map(|(address, class_hash)| (&address.0, class_hash))
+            .collect();
+        deployed_contracts.sort();
+        let mut nonces: Vec<_> = self
+            .nonces
+            .iter()
+            .map(|(address, nonce)| (&address.0, nonce))
+            .collect();
+        nonces.sort();
+
+        block::StateDiff {
+            storage_diffs,
+            deprecated_declared_classes: deprecated_declared_classes.into_iter().map(hex).collect(),
+            declared_classes: declared_classes
+                .into_iter()
+                .map(|(class_hash, compiled_class_hash)| block::DeclaredClass {
+                    class_hash: hex(class_hash),
+                    compiled_class_hash: felt_to_hex(compiled_class_hash),
+                })
+                .collect(),
+            deployed_contracts: deployed_contracts
+                .into_iter()
+                .map(|(address, class_hash)| block::DeployedContract {
+                    address: felt_to_hex(address),
+                    class_hash: hex(class_hash),
+                })
+                .collect(),
+            nonces: nonces
+                .into_iter()
+                .map(|(address, nonce)| block::NonceUpdate {
+                    contract_address: felt_to_hex(address),
+                    nonce: felt_to_hex(nonce),
+                })
+                .collect(),
+        }
+    }
+
+    fn merge(&mut self, other: StateDiff) {
+        self.declared_classes.extend(other.declared_classes);
+        self.sierra_classes.extend(other.sierra_classes);
+        self.compiled_class_hashes
+            .extend(other.compiled_class_hashes);
+        self.deployed_contracts.extend(other.deployed_contracts);
+        self.nonces.extend(other.nonces);
+        self.storage.extend(other.storage);
+    }
+}
+
+/// Value an entry had before being written on the current block, `None` if it wasn't written yet.
+#[derive(Clone, Debug)]
+enum Undo {
+    DeclaredClass(ClassHash, Option<String>),
+    SierraClass(ClassHash, Option<String>),
+    CompiledClassHash(ClassHash, Option<Felt252>),
+    Class(ClassHash, Option<ContractClass>),
+    DeployedContract(Address, Option<ClassHash>),
+    Nonce(Address, Option<Felt252>),
+    Storage(StorageEntry, Option<Felt252>),
+}
+
+/// Point of the current block that the state can be rolled back to.
+#[derive(Clone, Copy, Debug)]
+pub struct Checkpoint(usize);
+
+/// Starknet state of the application.
+/// Reads look at the writes of the block being executed before falling back to the committed state,
+/// so the rest of the node only sees a block's changes once `commit` is called.
+/// The committed state is shared between snapshots, and only copied if one of them commits while
+/// others are still around.
+#[derive(Clone, Debug, Default)]
+pub struct StarknetState {
+    committed: Arc<StateDiff>,
+    pending: StateDiff,
+    /// Parsed version of every committed class, so they are not parsed again on each execution.
+    classes: Arc<HashMap<ClassHash, ContractClass>>,
+    /// Parsed version of the classes set on the current block.
+    pending_classes: HashMap<ClassHash, ContractClass>,
+    /// Commitment over the committed state, updated with each committed block.
+    commitment: Arc<StateCommitment>,
+    /// Writes done on the current block, in order, so they can be undone down to a checkpoint.
+    journal: Vec<Undo>,
+}
+
+impl StarknetState {
+    /// Build the state from previously committed writes, parsing the declared classes.
+    pub fn from_committed(committed: StateDiff) -> Result<Self> {
+        let mut classes = HashMap::new();
+        for (class_hash, program) in committed.declared_classes.iter() {
+            classes.insert(*class_hash, ContractClass::try_from(program.as_str())?);
+        }
+
+        Ok(Self {
+            commitment: Arc::new(StateCommitment::new(&committed)),
+            committed: Arc::new(committed),
+            pending: StateDiff::default(),
+            classes: Arc::new(classes),
+            pending_classes: HashMap::new(),
+            journal: Vec::new(),
+        })
+    }
+
+    /// Store a class declared on the current block, along with its source so it gets persisted.
+    pub fn declare_class(
+        &mut self,
+        class_hash: ClassHash,
+        program: String,
+        contract_class: ContractClass,
+    ) {
+        let previous = self.pending.declared_classes.insert(class_hash, program);
+        self.journal.push(Undo::DeclaredClass(class_hash, previous));
+        self.set_class(class_hash, contract_class);
+    }
+
+    /// Store a Cairo 1 class declared on the current block, along with the hash of its compiled CASM.
+    pub fn declare_sierra_class(
+        &mut self,
+        class_hash: ClassHash,
+        sierra_class: String,
+        compiled_class_hash: Felt252,
+    ) {
+        let previous = self.pending.sierra_classes.insert(class_hash, sierra_class);
+        self.journal.push(Undo::SierraClass(class_hash, previous));
+        let previous = self
+            .pending
+            .compiled_class_hashes
+            .insert(class_hash, compiled_class_hash);
+        self.journal
+            .push(Undo::CompiledClassHash(class_hash, previous));
+    }
+
+    fn set_class(&mut self, class_hash: ClassHash, contract_class: ContractClass) {
+        let previous = self.pending_classes.insert(class_hash, contract_class);
+        self.journal.push(Undo::Class(class_hash, previous));
+    }
+
+    /// Whether the class is a Cairo 1 class, which can't be executed by the Cairo 0 VM in use.
+    pub fn is_sierra_class(&self, class_hash: &ClassHash) -> bool {
+        self.pending.sierra_classes.contains_key(class_hash)
+            || self.committed.sierra_classes.contains_key(class_hash)
+    }
+
+    /// State as of the last commit, without the writes of the block being executed.
+    pub fn committed(&self) -> &StateDiff {
+        &self.committed
+    }
+
+    /// Copy of the committed state, without the writes of the block being executed.
+    /// It shares the committed state with this one instead of copying it.
+    pub fn snapshot(&self) -> StarknetState {
+        Self {
+            committed: Arc::clone(&self.committed),
+            pending: StateDiff::default(),
+            classes: Arc::clone(&self.classes),
+            pending_classes: HashMap::new(),
+            commitment: Arc::clone(&self.commitment),
+            journal: Vec::new(),
+        }
+    }
+
+    /// Mark the writes done so far on the current block, so the ones after it can be undone if a
+    /// transaction fails.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint(self.journal.len())
+    }
+
+    /// Discard every write done after `checkpoint` was taken.
+    pub fn rollback(&mut self, checkpoint: Checkpoint) {
+        for undo in self.journal.drain(checkpoint.0..).rev() {
+            match undo {
+                Undo::DeclaredClass(key, value) => {
+                    restore(&mut self.pending.declared_classes, key, value)
+                }
+                Undo::SierraClass(key, value) => {
+                    restore(&mut self.pending.sierra_classes, key, value)
+                }
+                Undo::CompiledClassHash(key, value) => {
+                    restore(&mut self.pending.compiled_class_hashes, key, value)
+                }
+                Undo::Class(key, value) => restore(&mut self.pending_classes, key, value),
+                Undo::DeployedContract(key, value) => {
+                    restore(&mut self.pending.deployed_contracts, key, value)
+                }
+                Undo::Nonce(key, value) => restore(&mut self.pending.nonces, key, value),
+                Undo::Storage(key, value) => restore(&mut self.pending.storage, key, value),
+            }
+        }
+    }
+
+    /// Make the pending writes part of the committed state, returning them.
+    pub fn commit(&mut self) -> StateDiff {
+        self.journal.clear();
+        let pending = std::mem::take(&mut self.pending);
+        Arc::make_mut(&mut self.classes).extend(self.pending_classes.drain());
+        Arc::make_mut(&mut self.committed).merge(pending.clone());
+        Arc::make_mut(&mut self.commitment).update(&self.committed, &pending);
+        pending
+    }
+
+    /// Commitment over the committed state, used as the app hash.
+    pub fn commitment(&self) -> [u8; 32] {
+        self.commitment.root().to_bytes_be()
+    }
+}
+
+impl StateReader for StarknetState {
+    fn get_contract_class(&mut self, class_hash: &ClassHash) -> Result<ContractClass, StateError> {
+        self.pending_classes
+            .get(class_hash)
+            .or_else(|| self.classes.get(class_hash))
+            .cloned()
+            .ok_or(StateError::MissingClassHash())
+    }
+
+    fn get_class_hash_at(&mut self, contract_address: &Address) -> Result<&ClassHash, StateError> {
+        self.pending
+            .deployed_contracts
+            .get(contract_address)
+            .or_else(|| self.committed.deployed_contracts.get(contract_address))
+            .ok_or_else(|| StateError::NoneClassHash(contract_address.clone()))
+    }
+
+    fn get_nonce_at(&mut self, contract_address: &Address) -> Result<&Felt252, StateError> {
+        Ok(self
+            .pending
+            .nonces
+            .get(contract_address)
+            .or_else(|| self.committed.nonces.get(contract_address))
+            .unwrap_or(&ZERO))
+    }
+
+    fn get_storage_at(&mut self, storage_entry: &StorageEntry) -> Result<&Felt252, StateError> {
+        Ok(self
+            .pending
+            .storage
+            .get(storage_entry)
+            .or_else(|| self.committed.storage.get(storage_entry))
+            .unwrap_or(&ZERO))
+    }
+}
+
+impl State for StarknetState {
+    /// Classes set through this method are only kept in memory, since there is no source to persist.
+    /// Declare transactions should go through `declare_class` instead.
+    fn set_contract_class(
+        &mut self,
+        class_hash: &ClassHash,
+        contract_class: &ContractClass,
+    ) -> Result<(), StateError> {
+        self.set_class(*class_hash, contract_class.clone());
+        Ok(())
+    }
+
+    fn deploy_contract(
+        &mut self,
+        contract_address: Address,
+        class_hash: ClassHash,
+    ) -> Result<(), StateError> {
+        if self.get_class_hash_at(&contract_address).is_ok() {
+            return Err(StateError::ContractAddressUnavailable(contract_address));
+        }
+
+        let previous = self
+            .pending
+            .deployed_contracts
+            .insert(contract_address.clone(), class_hash);
+        self.journal
+            .push(Undo::DeployedContract(contract_address, previous));
+        Ok(())
+    }
+
+    fn increment_nonce(&mut self, contract_address: &Address) -> Result<(), StateError> {
+        let nonce = self.get_nonce_at(contract_address)?.clone() + Felt252::from(1);
+        let previous = self.pending.nonces.insert(contract_address.clone(), nonce);
+        self.journal
+            .push(Undo::Nonce(contract_address.clone(), previous));
+        Ok(())
+    }
+
+    fn set_storage_at(&mut self, storage_entry: &StorageEntry, value: Felt252) {
+        let previous = self.pending.storage.insert(storage_entry.clone(), value);
+        self.journal
+            .push(Undo::Storage(storage_entry.clone(), previous));
+    }
+}
+
+/// Put back the value an entry had, removing it if it had none.
+fn restore<K: Eq + Hash, V>(map: &mut HashMap<K, V>, key: K, value: Option<V>) {
+    match value {
+        Some(value) => map.insert(key, value),
+        None => map.remove(&key),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_diffs_are_sorted_by_address_and_key() {
+        let mut diff = StateDiff::default();
+        for (address, key) in [(0x10, 2), (0x2, 1), (0x10, 1)] {
+            diff.storage.insert(
+                (Address(address.into()), Felt252::from(key).to_be_bytes()),
+                Felt252::from(7),
+            );
+        }
+        diff.nonces.insert(Address(0x10.into()), 1.into());
+        diff.nonces.insert(Address(0x2.into()), 3.into());
+
+        let block_diff = diff.to_block_diff();
+        let addresses: Vec<_> = block_diff
+            .storage_diffs
+            .iter()
+            .map(|diff| diff.address.as_str())
+            .collect();
+        let keys: Vec<_> = block_diff.storage_diffs[1]
+            .storage_entries
+            .iter()
+            .map(|entry| entry.key.as_str())
+            .collect();
+
+        assert_eq!(addresses, ["0x2", "0x10"]);
+        assert_eq!(keys, ["0x1", "0x2"]);
+        assert_eq!(block_diff.nonces[0].contract_address, "0x2");
+        assert_eq!(block_diff.nonces[1].nonce, "0x1");
+    }
+
+    #[test]
+    fn commitment_updated_on_commit_matches_the_committed_state() {
+        let (address, other) = (Address(0x100.into()), Address(0x200.into()));
+        let mut genesis = StateDiff::default();
+        genesis
+            .deployed_contracts
+            .insert(address.clone(), Felt252::from(7).to_be_bytes());
+        genesis
+            .storage
+            .insert((address.clone(), [0; 32]), Felt252::from(1));
+        let mut state = StarknetState::from_committed(genesis).unwrap();
+
+        state.increment_nonce(&address).unwrap();
+        state.set_storage_at(&(address, [0; 32]), Felt252::from(2));
+        state.set_storage_at(&(other.clone(), Felt252::from(5).to_be_bytes()), 3.into());
+        state
+            .deploy_contract(other, Felt252::from(8).to_be_bytes())
+            .unwrap();
+        state.commit();
+
+        let rebuilt = StarknetState::from_committed(state.committed().clone()).unwrap();
+        assert_eq!(state.commitment(), rebuilt.commitment());
+    }
+
+    #[test]
+    fn rollback_restores_the_writes_done_before_the_checkpoint() {
+        let address = Address(0x100.into());
+        let entry = (address.clone(), [0; 32]);
+        let mut state = StarknetState::default();
+
+        state.set_storage_at(&entry, 1.into());
+        state.increment_nonce(&address).unwrap();
+        let checkpoint = state.checkpoint();
+        state.set_storage_at(&entry, 2.into());
+        state.increment_nonce(&address).unwrap();
+        state.set_storage_at(&(address.clone(), [1; 32]), 3.into());
+        state.deploy_contract(address.clone(), [0; 32]).unwrap();
+        state.rollback(checkpoint);
+
+        assert_eq!(*state.get_storage_at(&entry).unwrap(), 1.into());
+        assert_eq!(*state.get_nonce_at(&address).unwrap(), 1.into());
+        assert_eq!(state.pending.storage.len(), 1);
+        assert!(state.get_class_hash_at(&address).is_err());
+    }
+
+    #[test]
+    fn snapshots_share_the_committed_state_until_one_of_them_commits() {
+        let address = Address(0x100.into());
+        let mut state = StarknetState::default();
+        state.increment_nonce(&address).unwrap();
+        state.commit();
+
+        let mut snapshot = state.snapshot();
+        assert!(Arc::ptr_eq(&state.committed, &snapshot.committed));
+        snapshot.increment_nonce(&address).unwrap();
+        snapshot.commit();
+
+        assert!(!Arc::ptr_eq(&state.committed, &snapshot.committed));
+        assert_eq!(*state.get_nonce_at(&address).unwrap(), 1.into());
+        assert_eq!(*snapshot.get_nonce_at(&address).unwrap(), 2.into());
+        assert_ne!(state.commitment(), snapshot.commitment());
+    }
+}
@@ -0,0 +1,285 @@
+use crate::genesis::ChainConfig;
+use crate::state::{StarknetState, StateDiff};
+use anyhow::Result;
+use felt::Felt252;
+use lib::block::StateUpdate;
+use lib::execution::Receipt;
+use lib::{felt_to_hex, parse_felt};
+use starknet_rs::utils::{felt_to_hash, Address};
+use std::path::Path;
+
+const HEIGHT_KEY: &[u8] = b"meta/height";
+const APP_HASH_KEY: &[u8] = b"meta/app_hash";
+const CHAIN_CONFIG_KEY: &[u8] = b"meta/chain_config";
+const CLASS_PREFIX: &[u8] = b"class/";
+const SIERRA_CLASS_PREFIX: &[u8] = b"sierra_class/";
+const COMPILED_CLASS_HASH_PREFIX: &[u8] = b"compiled_class_hash/";
+const CONTRACT_PREFIX: &[u8] = b"contract/";
+const NONCE_PREFIX: &[u8] = b"nonce/";
+const STORAGE_PREFIX: &[u8] = b"storage/";
+const RECEIPT_PREFIX: &[u8] = b"receipt/";
+const BLOCK_PREFIX: &[u8] = b"block/";
+
+/// Embedded key-value store where the committed Starknet state is persisted, along with the
+/// last committed height and app hash.
+/// Everything lives in a single tree so that each commit can be written as one atomic batch.
+#[derive(Debug, Clone)]
+pub struct Storage {
+    db: sled::Db,
+}
+
+impl Storage {
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            db: sled::open(path)?,
+        })
+    }
+
+    /// Last committed block height, zero if nothing has been committed yet.
+    pub fn height(&self) -> Result<i64> {
+        match self.db.get(HEIGHT_KEY)? {
+            Some(bytes) => Ok(bincode::deserialize(&bytes)?),
+            None => Ok(0),
+        }
+    }
+
+    /// App hash returned on the last commit, empty if nothing has been committed yet.
+    pub fn app_hash(&self) -> Result<Vec<u8>> {
+        Ok(self
+            .db
+            .get(APP_HASH_KEY)?
+            .map(|bytes| bytes.to_vec())
+            .unwrap_or_default())
+    }
+
+    /// Chain parameters set by the genesis, or the defaults if it hasn't been loaded yet.
+    pub fn chain_config(&self) -> Result<ChainConfig> {
+        match self.db.get(CHAIN_CONFIG_KEY)? {
+            Some(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            None => Ok(ChainConfig::default()),
+        }
+    }
+
+    /// Persist the chain parameters set by the genesis. They are written before the genesis state is
+    /// committed, so a node that stops in between loads the genesis again.
+    pub fn set_chain_config(&self, config: &ChainConfig) -> Result<()> {
+        self.db
+            .insert(CHAIN_CONFIG_KEY, serde_json::to_vec(config)?)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Receipt of a committed transaction.
+    pub fn receipt(&self, transaction_hash: &Felt252) -> Result<Option<Receipt>> {
+        match self.db.get(receipt_key(transaction_hash))? {
+            Some(bytes) => Ok(Some(Receipt::decode(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// State update of a committed block.
+    pub fn state_update(&self, height: i64) -> Result<Option<StateUpdate>> {
+        let key = [BLOCK_PREFIX, &height.to_be_bytes()].concat();
+        match self.db.get(key)? {
+            Some(bytes) => Ok(Some(StateUpdate::decode(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Rebuild the committed Starknet state from the database.
+    pub fn load_state(&self) -> Result<StarknetState> {
+        let mut committed = StateDiff::default();
+
+        for entry in self.db.scan_prefix(CLASS_PREFIX) {
+            let (key, value) = entry?;
+            committed.declared_classes.insert(
+                key[CLASS_PREFIX.len()..].try_into()?,
+                String::from_utf8(value.to_vec())?,
+            );
+        }
+
+        for entry in self.db.scan_prefix(SIERRA_CLASS_PREFIX) {
+            let (key, value) = entry?;
+            committed.sierra_classes.insert(
+                key[SIERRA_CLASS_PREFIX.len()..].try_into()?,
+                String::from_utf8(value.to_vec())?,
+            );
+        }
+
+        for entry in self.db.scan_prefix(COMPILED_CLASS_HASH_PREFIX) {
+            let (key, value) = entry?;
+            committed.compiled_class_hashes.insert(
+                key[COMPILED_CLASS_HASH_PREFIX.len()..].try_into()?,
+                Felt252::from_bytes_be(&value),
+            );
+        }
+
+        for entry in self.db.scan_prefix(CONTRACT_PREFIX) {
+            let (key, value) = entry?;
+            committed.deployed_contracts.insert(
+                Address(Felt252::from_bytes_be(&key[CONTRACT_PREFIX.len()..])),
+                value.as_ref().try_into()?,
+            );
+        }
+
+        for entry in self.db.scan_prefix(NONCE_PREFIX) {
+            let (key, value) = entry?;
+            committed.nonces.insert(
+                Address(Felt252::from_bytes_be(&key[NONCE_PREFIX.len()..])),
+                Felt252::from_bytes_be(&value),
+            );
+        }
+
+        for entry in self.db.scan_prefix(STORAGE_PREFIX) {
+            let (key, value) = entry?;
+            let key = &key[STORAGE_PREFIX.len()..];
+            let address = Address(Felt252::from_bytes_be(&key[..32]));
+            committed.storage.insert(
+                (address, key[32..].try_into()?),
+                Felt252::from_bytes_be(&value),
+            );
+        }
+
+        StarknetState::from_committed(committed)
+    }
+
+    /// Atomically write the changes, receipts and state update of a block together with its height and app hash,
+    /// and flush them to disk.
+    pub fn commit(
+        &self,
+        height: i64,
+        diff: &StateDiff,
+        receipts: &[Receipt],
+        state_update: &StateUpdate,
+        app_hash: &[u8],
+    ) -> Result<()> {
+        let mut batch = sled::Batch::default();
+
+        for (class_hash, program) in diff.declared_classes.iter() {
+            batch.insert(key(CLASS_PREFIX, &[class_hash]), program.as_bytes());
+        }
+
+        for (class_hash, sierra_class) in diff.sierra_classes.iter() {
+            batch.insert(
+                key(SIERRA_CLASS_PREFIX, &[class_hash]),
+                sierra_class.as_bytes(),
+            );
+        }
+
+        for (class_hash, compiled_class_hash) in diff.compiled_class_hashes.iter() {
+            batch.insert(
+                key(COMPILED_CLASS_HASH_PREFIX, &[class_hash]),
+                compiled_class_hash.to_bytes_be(),
+            );
+        }
+
+        for (address, class_hash) in diff.deployed_contracts.iter() {
+            batch.insert(
+                key(CONTRACT_PREFIX, &[&felt_to_hash(&address.0)]),
+                class_hash.as_slice(),
+            );
+        }
+
+        for (address, nonce) in diff.nonces.iter() {
+            batch.insert(
+                key(NONCE_PREFIX, &[&felt_to_hash(&address.0)]),
+                nonce.to_bytes_be(),
+            );
+        }
+
+        for ((address, storage_key), value) in diff.storage.iter() {
+            batch.insert(
+                key(STORAGE_PREFIX, &[&felt_to_hash(&address.0), storage_key]),
+                value.to_bytes_be(),
+            );
+        }
+
+        for receipt in receipts.iter() {
+            batch.insert(
+                receipt_key(&parse_felt(&receipt.transaction_hash)?),
+                receipt.encode()?,
+            );
+        }
+
+        batch.insert(
+            [BLOCK_PREFIX, &height.to_be_bytes()].concat(),
+            state_update.encode()?,
+        );
+        batch.insert(HEIGHT_KEY, bincode::serialize(&height)?);
+        batch.insert(APP_HASH_KEY, app_hash);
+
+        self.db.apply_batch(batch)?;
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+/// Build a database key out of a prefix and fixed-size parts.
+fn key(prefix: &[u8], parts: &[&[u8; 32]]) -> Vec<u8> {
+    let mut key = prefix.to_vec();
+    for part in parts {
+        key.extend_from_slice(*part);
+    }
+    key
+}
+
+/// Receipts are keyed by the canonical spelling of the transaction hash, however it was sent.
+fn receipt_key(transaction_hash: &Felt252) -> Vec<u8> {
+    [RECEIPT_PREFIX, felt_to_hex(transaction_hash).as_bytes()].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::TempDir;
+    use retry::{delay::Fixed, retry};
+
+    #[test]
+    fn committed_state_survives_reopening_the_database() {
+        let dir = TempDir::new().unwrap();
+        let address = Address(Felt252::from(0x100));
+        let class_hash = felt_to_hash(&Felt252::from(7));
+        let sierra_class_hash = felt_to_hash(&Felt252::from(8));
+
+        let mut diff = StateDiff::default();
+        diff.deployed_contracts.insert(address.clone(), class_hash);
+        diff.nonces.insert(address.clone(), Felt252::from(3));
+        diff.storage
+            .insert((address.clone(), [1; 32]), Felt252::from(42));
+        diff.sierra_classes
+            .insert(sierra_class_hash, "{}".to_string());
+        diff.compiled_class_hashes
+            .insert(sierra_class_hash, Felt252::from(9));
+
+        let storage = Storage::open(dir.path()).unwrap();
+        storage
+            .commit(5, &diff, &[], &StateUpdate::default(), &[0xab; 32])
+            .unwrap();
+        let chain_config = ChainConfig {
+            max_block_steps: 1000,
+            ..Default::default()
+        };
+        storage.set_chain_config(&chain_config).unwrap();
+        // sled releases its lock on the database from a background thread once it is dropped
+        drop(storage);
+
+        let storage = retry(Fixed::from_millis(100).take(50), || {
+            Storage::open(dir.path())
+        })
+        .unwrap();
+        let state = storage.load_state().unwrap();
+        let committed = state.committed();
+        assert_eq!(storage.height().unwrap(), 5);
+        assert_eq!(storage.app_hash().unwrap(), vec![0xab; 32]);
+        assert_eq!(storage.chain_config().unwrap(), chain_config);
+        assert_eq!(committed.deployed_contracts, diff.deployed_contracts);
+        assert_eq!(committed.nonces, diff.nonces);
+        assert_eq!(committed.storage, diff.storage);
+        assert_eq!(committed.sierra_classes, diff.sierra_classes);
+        assert_eq!(committed.compiled_class_hashes, diff.compiled_class_hashes);
+        assert_eq!(
+            storage.state_update(5).unwrap(),
+            Some(StateUpdate::default())
+        );
+    }
+}
@@ -51,7 +51,7 @@ async fn main() {
 
     let transaction_type = TransactionType::Declare { program };
 
-    let transaction = Transaction::with_type(transaction_type).unwrap();
+    let transaction = Transaction::new(transaction_type, 0.into(), 0.into(), 0).unwrap();
     info!(
         "Single benchmark transaction size: {} bytes",
         bincode::serialize(&transaction).unwrap().len()
@@ -71,8 +71,7 @@ async fn main() {
             // clone the transactions with a different ID
             let t = Transaction {
                 id: Uuid::new_v4().to_string(),
-                transaction_hash: t.transaction_hash,
-                transaction_type: t.transaction_type,
+                ..t
             };
 
             transactions.push(bincode::serialize(&t).unwrap());
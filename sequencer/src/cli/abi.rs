@@ -0,0 +1,512 @@
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use felt::Felt252;
+use lib::{felt_to_hex, parse_felt};
+use num_traits::ToPrimitive;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+/// Functions and structs declared in the ABI of a compiled Cairo 0 contract, used to turn
+/// JSON arguments into calldata and return data back into JSON.
+///
+/// Array parameters follow Cairo's convention of an `<name>_len: felt` parameter followed by
+/// `<name>: T*`. Callers pass the array alone and its length is filled in.
+#[derive(Debug, Default)]
+pub struct Abi {
+    functions: HashMap<String, AbiFunction>,
+    structs: HashMap<String, AbiStruct>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AbiEntry {
+    Function(AbiFunction),
+    Constructor(AbiFunction),
+    L1Handler(AbiFunction),
+    Struct(AbiStruct),
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AbiFunction {
+    name: String,
+    inputs: Vec<AbiMember>,
+    #[serde(default)]
+    outputs: Vec<AbiMember>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AbiMember {
+    name: String,
+    r#type: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct AbiStruct {
+    name: String,
+    members: Vec<AbiStructMember>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AbiStructMember {
+    name: String,
+    r#type: String,
+    offset: usize,
+}
+
+/// A function parameter or output as seen by the user, with array lengths folded into their array.
+struct Parameter<'a> {
+    name: &'a str,
+    r#type: &'a str,
+    is_array: bool,
+}
+
+impl Abi {
+    /// Read the ABI out of a compiled contract, as passed to `declare` or returned by a class query.
+    pub fn from_program(program: &str) -> Result<Self> {
+        let program: Value = serde_json::from_str(program)?;
+        let entries = program
+            .get("abi")
+            .ok_or_else(|| anyhow!("Contract has no ABI"))?;
+        let entries: Vec<AbiEntry> = serde_json::from_value(entries.clone())?;
+
+        let mut abi = Abi::default();
+        for entry in entries {
+            match entry {
+                AbiEntry::Function(function)
+                | AbiEntry::Constructor(function)
+                | AbiEntry::L1Handler(function) => {
+                    abi.functions.insert(function.name.clone(), function);
+                }
+                AbiEntry::Struct(r#struct) => {
+                    abi.structs.insert(r#struct.name.clone(), r#struct);
+                }
+                AbiEntry::Other => {}
+            }
+        }
+
+        Ok(abi)
+    }
+
+    pub fn function(&self, name: &str) -> Result<&AbiFunction> {
+        self.functions.get(name).ok_or_else(|| {
+            let mut names: Vec<&str> = self.functions.keys().map(String::as_str).collect();
+            names.sort();
+            anyhow!(
+                "Function {name} is not in the contract ABI, available functions are: {}",
+                names.join(", ")
+            )
+        })
+    }
+
+    /// Encode the arguments of a function, given either as a JSON array in declaration order or as
+    /// a JSON object keyed by parameter name.
+    pub fn encode_inputs(&self, function: &str, args: &Value) -> Result<Vec<Felt252>> {
+        let function = self.function(function)?;
+        let parameters = parameters(&function.inputs);
+        let names: Vec<&str> = parameters.iter().map(|parameter| parameter.name).collect();
+
+        let values: Vec<&Value> = match args {
+            Value::Array(values) => {
+                ensure!(
+                    values.len() == parameters.len(),
+                    "Function {} takes {} arguments ({}), got {}",
+                    function.name,
+                    parameters.len(),
+                    names.join(", "),
+                    values.len()
+                );
+                values.iter().collect()
+            }
+            Value::Object(values) => {
+                if let Some(unknown) = values.keys().find(|key| !names.contains(&key.as_str())) {
+                    bail!("Function {} has no argument {unknown}", function.name);
+                }
+                parameters
+                    .iter()
+                    .map(|parameter| {
+                        values.get(parameter.name).ok_or_else(|| {
+                            anyhow!("Missing argument {} of {}", parameter.name, function.name)
+                        })
+                    })
+                    .collect::<Result<_>>()?
+            }
+            _ => bail!("Arguments must be a JSON array or object"),
+        };
+
+        let mut calldata = Vec::new();
+        for (parameter, value) in parameters.iter().zip(values) {
+            let encoded = if parameter.is_array {
+                self.encode_array(parameter.r#type, value, &mut calldata)
+            } else {
+                self.encode_value(parameter.r#type, value, &mut calldata)
+            };
+            encoded.with_context(|| format!("Invalid argument {}", parameter.name))?;
+        }
+
+        Ok(calldata)
+    }
+
+    /// Decode the return data of a function into a JSON object keyed by output name.
+    pub fn decode_outputs(&self, function: &str, retdata: &[Felt252]) -> Result<Value> {
+        let function = self.function(function)?;
+        let mut retdata = retdata.iter();
+
+        let mut outputs = Map::new();
+        for parameter in parameters(&function.outputs) {
+            let value = if parameter.is_array {
+                self.decode_array(parameter.r#type, &mut retdata)
+            } else {
+                self.decode_value(parameter.r#type, &mut retdata)
+            };
+            outputs.insert(parameter.name.to_string(), value?);
+        }
+
+        ensure!(
+            retdata.next().is_none(),
+            "Return data is longer than the outputs of {}",
+            function.name
+        );
+        Ok(Value::Object(outputs))
+    }
+
+    /// Encode a `T*` parameter as its length followed by its elements.
+    fn encode_array(&self, r#type: &str, value: &Value, out: &mut Vec<Felt252>) -> Result<()> {
+        let Value::Array(elements) = value else {
+            bail!("Expected an array of {}", element_type(r#type));
+        };
+
+        out.push(elements.len().into());
+        for element in elements {
+            self.encode_value(element_type(r#type), element, out)?;
+        }
+        Ok(())
+    }
+
+    fn encode_value(&self, r#type: &str, value: &Value, out: &mut Vec<Felt252>) -> Result<()> {
+        if r#type == "felt" {
+            out.push(felt_from_json(value)?);
+            return Ok(());
+        }
+
+        // Uint256 can also be given as a single number, split into its low and high halves
+        if r#type == "Uint256" && !value.is_object() {
+            let (low, high) = uint256_from_json(value)?;
+            out.extend([low, high]);
+            return Ok(());
+        }
+
+        if let Some(types) = tuple_types(r#type) {
+            let Value::Array(values) = value else {
+                bail!("Expected an array for tuple {}", r#type);
+            };
+            ensure!(
+                values.len() == types.len(),
+                "Tuple {} has {} elements, got {}",
+                r#type,
+                types.len(),
+                values.len()
+            );
+            for ((_, r#type), value) in types.iter().zip(values) {
+                self.encode_value(r#type, value, out)?;
+            }
+            return Ok(());
+        }
+
+        let r#struct = self.r#struct(r#type)?;
+        let Value::Object(values) = value else {
+            bail!("Expected an object for struct {}", r#type);
+        };
+        for member in r#struct.members() {
+            let value = values
+                .get(&member.name)
+                .ok_or_else(|| anyhow!("Missing member {} of {}", member.name, r#type))?;
+            self.encode_value(&member.r#type, value, out)
+                .with_context(|| format!("Invalid member {} of {}", member.name, r#type))?;
+        }
+        Ok(())
+    }
+
+    fn decode_array<'a>(
+        &self,
+        r#type: &str,
+        retdata: &mut impl Iterator<Item = &'a Felt252>,
+    ) -> Result<Value> {
+        let len = next_felt(retdata)?
+            .to_usize()
+            .ok_or_else(|| anyhow!("Invalid array length"))?;
+
+        (0..len)
+            .map(|_| self.decode_value(element_type(r#type), retdata))
+            .collect()
+    }
+
+    fn decode_value<'a>(
+        &self,
+        r#type: &str,
+        retdata: &mut impl Iterator<Item = &'a Felt252>,
+    ) -> Result<Value> {
+        if r#type == "felt" {
+            return Ok(Value::String(felt_to_hex(next_felt(retdata)?)));
+        }
+
+        if r#type == "Uint256" {
+            let low = next_felt(retdata)?;
+            let high = next_felt(retdata)?;
+            return Ok(Value::String(uint256_to_hex(low, high)));
+        }
+
+        if let Some(types) = tuple_types(r#type) {
+            let named = types.iter().all(|(name, _)| name.is_some());
+            let mut values = Map::new();
+            let mut elements = Vec::new();
+            for (name, r#type) in types {
+                let value = self.decode_value(r#type, retdata)?;
+                match name {
+                    Some(name) if named => {
+                        values.insert(name.to_string(), value);
+                    }
+                    _ => elements.push(value),
+                }
+            }
+            return Ok(if named {
+                Value::Object(values)
+            } else {
+                Value::Array(elements)
+            });
+        }
+
+        let mut values = Map::new();
+        for member in self.r#struct(r#type)?.members() {
+            values.insert(
+                member.name.clone(),
+                self.decode_value(&member.r#type, retdata)?,
+            );
+        }
+        Ok(Value::Object(values))
+    }
+
+    fn r#struct(&self, r#type: &str) -> Result<&AbiStruct> {
+        ensure!(
+            !r#type.ends_with('*'),
+            "Pointers are only supported as array parameters, found {}",
+            r#type
+        );
+        self.structs
+            .get(r#type)
+            .ok_or_else(|| anyhow!("Unknown type {}", r#type))
+    }
+}
+
+impl AbiStruct {
+    /// Members in memory order.
+    fn members(&self) -> Vec<&AbiStructMember> {
+        let mut members: Vec<&AbiStructMember> = self.members.iter().collect();
+        members.sort_by_key(|member| member.offset);
+        members
+    }
+}
+
+/// Fold `<name>_len: felt, <name>: T*` pairs into a single array parameter.
+fn parameters(members: &[AbiMember]) -> Vec<Parameter<'_>> {
+    let mut parameters: Vec<Parameter> = Vec::new();
+
+    for member in members {
+        let is_array = member.r#type.ends_with('*');
+        if is_array
+            && parameters
+                .last()
+                .is_some_and(|last| last.name == format!("{}_len", member.name))
+        {
+            parameters.pop();
+        }
+        parameters.push(Parameter {
+            name: &member.name,
+            r#type: &member.r#type,
+            is_array,
+        });
+    }
+
+    parameters
+}
+
+fn element_type(r#type: &str) -> &str {
+    r#type.strip_suffix('*').unwrap_or(r#type)
+}
+
+/// Element types of a tuple type such as `(felt, felt)` or `(x: felt, y: felt)`, along with their names.
+fn tuple_types(r#type: &str) -> Option<Vec<(Option<&str>, &str)>> {
+    let inner = r#type.strip_prefix('(')?.strip_suffix(')')?;
+
+    let mut types = Vec::new();
+    let (mut depth, mut start) = (0, 0);
+    for (i, c) in inner.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                types.push(&inner[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    types.push(&inner[start..]);
+
+    Some(
+        types
+            .into_iter()
+            .map(str::trim)
+            .filter(|element| !element.is_empty())
+            .map(|element| match element.split_once(':') {
+                Some((name, r#type)) if !name.contains('(') => (Some(name.trim()), r#type.trim()),
+                _ => (None, element),
+            })
+            .collect(),
+    )
+}
+
+fn next_felt<'a>(retdata: &mut impl Iterator<Item = &'a Felt252>) -> Result<&'a Felt252> {
+    retdata
+        .next()
+        .ok_or_else(|| anyhow!("Return data is shorter than the function outputs"))
+}
+
+/// A felt given as a JSON number or as a hex, decimal or 'short string' string.
+fn felt_from_json(value: &Value) -> Result<Felt252> {
+    match value {
+        Value::String(value) => parse_felt(value),
+        Value::Number(number) => number
+            .as_u64()
+            .map(Felt252::from)
+            .ok_or_else(|| anyhow!("Expected a non-negative integer, got {number}")),
+        _ => bail!("Expected a felt, got {value}"),
+    }
+}
+
+/// Split a 256-bit number given as a hex string, or as anything `felt_from_json` accepts, into
+/// its low and high 128 bits.
+fn uint256_from_json(value: &Value) -> Result<(Felt252, Felt252)> {
+    let bytes = match value.as_str().and_then(|value| value.strip_prefix("0x")) {
+        Some(hex) => {
+            ensure!(hex.len() <= 64, "Uint256 value out of range: 0x{hex}");
+            hex::decode(format!("{hex:0>64}"))?
+        }
+        None => felt_from_json(value)?.to_be_bytes().to_vec(),
+    };
+    let (high, low) = bytes.split_at(16);
+
+    Ok((Felt252::from_bytes_be(low), Felt252::from_bytes_be(high)))
+}
+
+fn uint256_to_hex(low: &Felt252, high: &Felt252) -> String {
+    let mut bytes = high.to_be_bytes()[16..].to_vec();
+    bytes.extend_from_slice(&low.to_be_bytes()[16..]);
+
+    let hex = hex::encode(bytes);
+    match hex.trim_start_matches('0') {
+        "" => "0x0".to_string(),
+        hex => format!("0x{hex}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn abi() -> Abi {
+        let program = json!({
+            "abi": [
+                {"type": "struct", "name": "Uint256", "size": 2, "members": [
+                    {"name": "low", "offset": 0, "type": "felt"},
+                    {"name": "high", "offset": 1, "type": "felt"}
+                ]},
+                {"type": "struct", "name": "Point", "size": 2, "members": [
+                    {"name": "y", "offset": 1, "type": "felt"},
+                    {"name": "x", "offset": 0, "type": "felt"}
+                ]},
+                {"type": "function", "name": "transfer", "inputs": [
+                    {"name": "recipient", "type": "felt"},
+                    {"name": "amount", "type": "Uint256"},
+                    {"name": "points_len", "type": "felt"},
+                    {"name": "points", "type": "Point*"}
+                ], "outputs": [
+                    {"name": "balance", "type": "Uint256"},
+                    {"name": "values_len", "type": "felt"},
+                    {"name": "values", "type": "felt*"},
+                    {"name": "pair", "type": "(a: felt, b: felt)"}
+                ]},
+                {"type": "event", "name": "Transfer", "data": [], "keys": []}
+            ]
+        });
+
+        Abi::from_program(&program.to_string()).unwrap()
+    }
+
+    #[test]
+    fn arguments_are_encoded_following_the_abi() {
+        let expected: Vec<Felt252> = vec![
+            0xabc.into(),
+            5.into(),
+            0.into(),
+            2.into(),
+            1.into(),
+            2.into(),
+            3.into(),
+            4.into(),
+        ];
+
+        let positional = json!(["0xabc", 5, [{"x": 1, "y": 2}, {"x": "3", "y": "0x4"}]]);
+        assert_eq!(
+            abi().encode_inputs("transfer", &positional).unwrap(),
+            expected
+        );
+
+        let named = json!({
+            "points": [{"x": 1, "y": 2}, {"x": 3, "y": 4}],
+            "amount": {"low": 5, "high": 0},
+            "recipient": "0xabc",
+        });
+        assert_eq!(abi().encode_inputs("transfer", &named).unwrap(), expected);
+
+        assert!(abi()
+            .encode_inputs("transfer", &json!(["0xabc", 5]))
+            .is_err());
+        assert!(abi().encode_inputs("mint", &json!([])).is_err());
+    }
+
+    #[test]
+    fn uint256_values_are_split_into_halves() {
+        let (low, high) = uint256_from_json(&json!(format!("0x1{}", "0".repeat(32)))).unwrap();
+        assert_eq!((low, high), (0.into(), 1.into()));
+        assert_eq!(
+            uint256_to_hex(&0.into(), &1.into()),
+            format!("0x1{}", "0".repeat(32))
+        );
+    }
+
+    #[test]
+    fn return_data_is_decoded_following_the_abi() {
+        let retdata: Vec<Felt252> = vec![
+            0x10.into(),
+            0.into(),
+            2.into(),
+            7.into(),
+            8.into(),
+            1.into(),
+            2.into(),
+        ];
+
+        assert_eq!(
+            abi().decode_outputs("transfer", &retdata).unwrap(),
+            json!({
+                "balance": "0x10",
+                "values": ["0x7", "0x8"],
+                "pair": {"a": "0x1", "b": "0x2"},
+            })
+        );
+        assert!(abi().decode_outputs("transfer", &retdata[..4]).is_err());
+    }
+}
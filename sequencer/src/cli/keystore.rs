@@ -0,0 +1,99 @@
+use anyhow::{anyhow, Context, Result};
+use felt::Felt252;
+use lib::hash::{felt_to_field_element, field_element_to_felt};
+use lib::{felt_to_hex, parse_felt};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use starknet_crypto::{get_public_key, rfc6979_generate_k, sign};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+/// Signing key of an account, stored in a local JSON file.
+/// The private key is kept unencrypted, so keystores are only meant for development networks.
+#[derive(Serialize, Deserialize)]
+pub struct Keystore {
+    private_key: String,
+    public_key: String,
+}
+
+impl Keystore {
+    /// Create a keystore with a random private key.
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        // keep the key below 2^251, which is below the order of the curve
+        bytes[0] &= 0x07;
+
+        let private_key = Felt252::from_bytes_be(&bytes);
+        let public_key = get_public_key(&felt_to_field_element(&private_key));
+
+        Self {
+            private_key: felt_to_hex(&private_key),
+            public_key: felt_to_hex(&field_element_to_felt(&public_key)),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path).with_context(|| {
+            format!(
+                "Could not read keystore at {}, create one with the keygen command",
+                path.display()
+            )
+        })?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    /// Write the keystore to `path`, failing if a file already exists there so keys are never overwritten.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .with_context(|| format!("Could not create keystore at {}", path.display()))?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn public_key(&self) -> Result<Felt252> {
+        parse_felt(&self.public_key)
+    }
+
+    /// ECDSA signature over `hash` on the STARK curve, as the `[r, s]` pair expected by accounts.
+    pub fn sign(&self, hash: &Felt252) -> Result<Vec<Felt252>> {
+        let private_key = felt_to_field_element(&parse_felt(&self.private_key)?);
+        let message = felt_to_field_element(hash);
+        let k = rfc6979_generate_k(&message, &private_key, None);
+
+        let signature =
+            sign(&private_key, &message, &k).map_err(|e| anyhow!("Could not sign: {e}"))?;
+        Ok(vec![
+            field_element_to_felt(&signature.r),
+            field_element_to_felt(&signature.s),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use starknet_crypto::verify;
+
+    #[test]
+    fn signatures_verify_against_the_public_key() {
+        let keystore = Keystore::generate();
+        let hash = Felt252::from(0x1234);
+
+        let signature = keystore.sign(&hash).unwrap();
+
+        let public_key = felt_to_field_element(&keystore.public_key().unwrap());
+        let valid = verify(
+            &public_key,
+            &felt_to_field_element(&hash),
+            &felt_to_field_element(&signature[0]),
+            &felt_to_field_element(&signature[1]),
+        )
+        .unwrap();
+        assert!(valid);
+    }
+}
@@ -1,14 +1,23 @@
-use crate::tendermint::broadcast;
-use anyhow::{bail, Result};
+use crate::abi::Abi;
+use crate::keystore::Keystore;
+use crate::tendermint::{broadcast, CommittedTransaction};
+use anyhow::{anyhow, bail, Result};
 use clap::{Args, Parser, Subcommand};
-use lib::{Transaction, TransactionType};
-use serde_json::json;
+use felt::Felt252;
+use lib::account::selector;
+use lib::query::{Query, QueryResponse};
+use lib::sierra::{CasmClass, SierraClass};
+use lib::{felt_to_hex, parse_felt, Transaction, TransactionType};
+use serde_json::{json, Value};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str;
+use std::time::Duration;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::EnvFilter;
 
+pub mod abi;
+pub mod keystore;
 pub mod tendermint;
 const LOCAL_SEQUENCER_URL: &str = "http://127.0.0.1:26657";
 
@@ -29,14 +38,33 @@ struct Cli {
     /// Tendermint node url
     #[clap(short, long, env = "SEQUENCER_URL", default_value = LOCAL_SEQUENCER_URL)]
     pub url: String,
+
+    /// Keystore holding the key transactions are signed with
+    #[clap(
+        short,
+        long,
+        env = "SEQUENCER_KEYSTORE",
+        default_value = "keystore.json"
+    )]
+    pub keystore: PathBuf,
 }
 
 #[derive(Subcommand)]
 enum Command {
+    /// Create a keystore with a new signing key, and print its public key
+    Keygen,
     Declare(DeclareArgs),
     DeployAccount(DeployArgs),
     Invoke(InvokeArgs),
+    Call(CallArgs),
+    Receipt(ReceiptArgs),
+    /// Print the changes a committed block made to the state
+    Block(BlockArgs),
     Get(GetArgs),
+    Class(ClassArgs),
+    ClassHashAt(ContractArgs),
+    Storage(StorageArgs),
+    Nonce(ContractArgs),
 }
 
 #[derive(Args)]
@@ -44,39 +72,152 @@ pub struct GetArgs {
     transaction_id: String,
 }
 
+#[derive(Args)]
+pub struct ReceiptArgs {
+    /// Hash of the transaction
+    #[arg(value_parser = parse_felt)]
+    transaction_hash: Felt252,
+}
+
+#[derive(Args)]
+pub struct BlockArgs {
+    /// Height of the block
+    height: i64,
+}
+
+#[derive(Args)]
+pub struct ClassArgs {
+    /// Hash of the declared class
+    #[arg(value_parser = parse_felt)]
+    class_hash: Felt252,
+}
+
+#[derive(Args)]
+pub struct ContractArgs {
+    /// Contract Address
+    #[arg(value_parser = parse_felt)]
+    address: Felt252,
+}
+
+#[derive(Args)]
+pub struct StorageArgs {
+    /// Contract Address
+    #[arg(value_parser = parse_felt)]
+    address: Felt252,
+
+    /// Storage address to read
+    #[arg(value_parser = parse_felt)]
+    key: Felt252,
+}
+
+/// Fields shared by every transaction.
+#[derive(Args, Debug)]
+pub struct TransactionArgs {
+    /// Nonce of the sender account, fetched from the sequencer if not given
+    #[arg(long, value_parser = parse_felt)]
+    nonce: Option<Felt252>,
+
+    /// Maximum fee the sender is willing to pay
+    #[arg(long, default_value_t = 0)]
+    max_fee: u64,
+
+    /// Wait for the transaction to be committed, and print its block height, DeliverTx code and receipt
+    #[arg(long, default_value_t = false)]
+    wait: bool,
+
+    /// Seconds to wait for the transaction to be committed
+    #[arg(long, default_value_t = 30, requires = "wait")]
+    timeout: u64,
+}
+
 #[derive(Args)]
 pub struct DeclareArgs {
+    /// Compiled contract class, either a Cairo 0 class or a Cairo 1 Sierra class
     #[arg(long)]
     contract: PathBuf,
+
+    /// CASM a Cairo 1 class compiles to (the output of `starknet-sierra-compile`), required to declare Sierra classes
+    #[arg(long)]
+    casm: Option<PathBuf>,
+
+    /// Address of the account contract sending the transaction, which validates and pays for it
+    #[arg(long, env = "ACCOUNT_ADDRESS", value_parser = parse_felt)]
+    sender: Felt252,
+
+    #[command(flatten)]
+    transaction: TransactionArgs,
 }
 
 #[derive(Args)]
 pub struct DeployArgs {
-    class_hash: String,
+    #[arg(value_parser = parse_felt)]
+    class_hash: Felt252,
     // TODO: randomize salt by default?
-    #[arg(long, default_value = "1111")]
-    salt: i32,
-    #[arg(long, num_args=1.., value_delimiter = ' ')]
-    inputs: Option<Vec<i32>>,
+    #[arg(long, default_value = "1111", value_parser = parse_felt)]
+    salt: Felt252,
+    /// Constructor input values, such as the public key of the account, as hex, decimal or 'short string' felts
+    #[arg(long, num_args=1.., value_delimiter = ' ', value_parser = parse_felt)]
+    inputs: Option<Vec<Felt252>>,
+
+    #[command(flatten)]
+    transaction: TransactionArgs,
 }
 
 #[derive(Args, Debug)]
 pub struct InvokeArgs {
+    /// Address of the account contract sending the transaction
+    #[clap(short, long, env = "ACCOUNT_ADDRESS", value_parser = parse_felt)]
+    sender: Felt252,
+
     /// Contract Address
-    #[clap(short, long)]
-    address: String,
+    #[clap(short, long, value_parser = parse_felt)]
+    address: Felt252,
 
     /// Function name
     #[clap(short, long)]
     function: String,
 
-    /// Function input values
-    #[clap(long, num_args=1.., value_delimiter = ' ')]
-    inputs: Option<Vec<i32>>,
+    /// Function input values, as hex, decimal or 'short string' felts
+    #[clap(long, num_args=1.., value_delimiter = ' ', value_parser = parse_felt)]
+    inputs: Option<Vec<Felt252>>,
+
+    /// Function arguments as a JSON array or object, encoded following the contract ABI
+    #[clap(long, conflicts_with = "inputs")]
+    args: Option<String>,
+
+    /// Compiled contract to read the ABI from, instead of fetching the class of the contract
+    #[clap(long)]
+    abi: Option<PathBuf>,
 
     /// tendermint node url
     #[clap(short, long, env = "SEQUENCER_URL", default_value = LOCAL_SEQUENCER_URL)]
     pub url: String,
+
+    #[command(flatten)]
+    transaction: TransactionArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct CallArgs {
+    /// Contract Address
+    #[clap(short, long, value_parser = parse_felt)]
+    address: Felt252,
+
+    /// Function name
+    #[clap(short, long)]
+    function: String,
+
+    /// Function input values, as hex, decimal or 'short string' felts
+    #[clap(long, num_args=1.., value_delimiter = ' ', value_parser = parse_felt)]
+    inputs: Option<Vec<Felt252>>,
+
+    /// Function arguments as a JSON array or object, encoded following the contract ABI
+    #[clap(long, conflicts_with = "inputs")]
+    args: Option<String>,
+
+    /// Compiled contract to read the ABI from, instead of fetching the class of the contract
+    #[clap(long)]
+    abi: Option<PathBuf>,
 }
 
 #[tokio::main()]
@@ -94,16 +235,75 @@ async fn main() {
     }
 
     let result = match cli.command {
-        Command::Declare(declare_args) => do_declare(declare_args, &cli.url).await,
-        Command::DeployAccount(deploy_args) => do_deploy(deploy_args, &cli.url).await,
-        Command::Invoke(invoke_args) => do_invoke(invoke_args, &cli.url).await,
-        Command::Get(get_args) => {
-            tendermint::get_transaction(&get_args.transaction_id, &cli.url).await
+        Command::Keygen => do_keygen(&cli.keystore),
+        Command::Declare(declare_args) => do_declare(declare_args, &cli.url, &cli.keystore).await,
+        Command::DeployAccount(deploy_args) => {
+            do_deploy(deploy_args, &cli.url, &cli.keystore).await
+        }
+        Command::Invoke(invoke_args) => do_invoke(invoke_args, &cli.url, &cli.keystore).await,
+        Command::Call(args) => do_call(args, &cli.url).await,
+        Command::Receipt(args) => {
+            do_query(
+                Query::Receipt {
+                    transaction_hash: args.transaction_hash,
+                },
+                &cli.url,
+            )
+            .await
+        }
+        Command::Block(args) => {
+            do_query(
+                Query::StateUpdate {
+                    block_number: args.height,
+                },
+                &cli.url,
+            )
+            .await
+        }
+        Command::Get(get_args) => tendermint::get_transaction(&get_args.transaction_id, &cli.url)
+            .await
+            .map(committed_output),
+        Command::Class(args) => {
+            do_query(
+                Query::Class {
+                    class_hash: args.class_hash,
+                },
+                &cli.url,
+            )
+            .await
+        }
+        Command::ClassHashAt(args) => {
+            do_query(
+                Query::ClassHashAt {
+                    address: args.address,
+                },
+                &cli.url,
+            )
+            .await
+        }
+        Command::Storage(args) => {
+            do_query(
+                Query::StorageAt {
+                    address: args.address,
+                    key: args.key,
+                },
+                &cli.url,
+            )
+            .await
+        }
+        Command::Nonce(args) => {
+            do_query(
+                Query::Nonce {
+                    address: args.address,
+                },
+                &cli.url,
+            )
+            .await
         }
     };
 
     let (code, output) = match result {
-        Ok(output) => (0, json!({"id": output.id,"hash": output.transaction_hash})),
+        Ok(output) => (0, output),
         Err(err) => (1, json!({"error": err.to_string()})),
     };
 
@@ -111,46 +311,206 @@ async fn main() {
     std::process::exit(code);
 }
 
-async fn do_declare(args: DeclareArgs, url: &str) -> Result<Transaction> {
-    let program = fs::read_to_string(args.contract)?;
-    let transaction_type = TransactionType::Declare { program };
-    let transaction = Transaction::with_type(transaction_type)?;
-    let transaction_serialized = bincode::serialize(&transaction)?;
+fn transaction_output(transaction: Transaction) -> Value {
+    json!({"id": transaction.id, "hash": transaction.transaction_hash})
+}
+
+fn committed_output(committed: CommittedTransaction) -> Value {
+    let mut output = transaction_output(committed.transaction);
+    output["height"] = json!(committed.receipt.block_height);
+    output["code"] = json!(committed.code);
+    output["receipt"] = json!(committed.receipt);
+    output
+}
+
+async fn do_query(query: Query, url: &str) -> Result<Value> {
+    let response = tendermint::query(query, url).await?;
+    Ok(serde_json::to_value(response)?)
+}
+
+fn do_keygen(keystore_path: &Path) -> Result<Value> {
+    let keystore = Keystore::generate();
+    keystore.save(keystore_path)?;
+
+    Ok(json!({
+        "keystore": keystore_path,
+        "public_key": felt_to_hex(&keystore.public_key()?),
+    }))
+}
+
+/// Nonce to send a transaction with: the given one, or else the next nonce of the sender as reported by the sequencer.
+async fn next_nonce(
+    args: &TransactionArgs,
+    sender_address: &Felt252,
+    url: &str,
+) -> Result<Felt252> {
+    if let Some(nonce) = &args.nonce {
+        return Ok(nonce.clone());
+    }
+
+    let query = Query::Nonce {
+        address: sender_address.clone(),
+    };
+    match tendermint::query(query, url).await? {
+        QueryResponse::Nonce { nonce } => parse_felt(&nonce),
+        response => bail!("Unexpected response to nonce query: {response:?}"),
+    }
+}
 
-    match tendermint::broadcast(transaction_serialized, url).await {
-        Ok(_) => Ok(transaction),
-        Err(e) => bail!("DECLARE: Error ocurred when sending out transaction: {e}"),
+/// Broadcast a transaction and output it. With `--wait`, it waits for the transaction to be committed
+/// and adds its block height, DeliverTx code and receipt.
+async fn send_transaction(
+    transaction: Transaction,
+    args: &TransactionArgs,
+    url: &str,
+) -> Result<Value> {
+    if !args.wait {
+        broadcast(bincode::serialize(&transaction)?, url).await?;
+        return Ok(transaction_output(transaction));
     }
+
+    let timeout = Duration::from_secs(args.timeout);
+    let committed = tendermint::broadcast_and_wait(transaction, url, timeout).await?;
+    Ok(committed_output(committed))
+}
+
+/// Sign a transaction with the key held by the keystore.
+fn sign(transaction: Transaction, keystore_path: &Path) -> Result<Transaction> {
+    let keystore = Keystore::load(keystore_path)?;
+
+    Ok(Transaction {
+        signature: keystore.sign(&transaction.hash()?)?,
+        ..transaction
+    })
 }
 
-async fn do_deploy(args: DeployArgs, url: &str) -> Result<Transaction> {
+async fn do_declare(args: DeclareArgs, url: &str, keystore: &Path) -> Result<Value> {
+    let program = fs::read_to_string(args.contract)?;
+    let transaction_type = if SierraClass::is_sierra(&program) {
+        let Some(casm) = args.casm else {
+            bail!("Declaring a Cairo 1 class requires its compiled --casm");
+        };
+        let casm_class = fs::read_to_string(casm)?;
+        let compiled_class_hash =
+            CasmClass::from_json(&casm_class)?.compute_compiled_class_hash()?;
+        TransactionType::DeclareV2 {
+            sierra_class: program,
+            casm_class,
+            compiled_class_hash,
+        }
+    } else {
+        TransactionType::Declare { program }
+    };
+    let nonce = next_nonce(&args.transaction, &args.sender, url).await?;
+    let transaction = Transaction::new(
+        transaction_type,
+        args.sender,
+        nonce,
+        args.transaction.max_fee,
+    )?;
+    let transaction = sign(transaction, keystore)?;
+
+    send_transaction(transaction, &args.transaction, url)
+        .await
+        .map_err(|e| anyhow!("DECLARE: Error ocurred when sending out transaction: {e}"))
+}
+
+async fn do_deploy(args: DeployArgs, url: &str, keystore: &Path) -> Result<Value> {
     let transaction_type = TransactionType::DeployAccount {
         class_hash: args.class_hash,
         salt: args.salt,
-        inputs: args.inputs,
+        constructor_calldata: args.inputs.unwrap_or_default(),
     };
 
-    let transaction = Transaction::with_type(transaction_type)?;
-    let transaction_serialized = bincode::serialize(&transaction)?;
+    // the account being deployed is the one sending the transaction
+    let sender_address = transaction_type.contract_address()?;
+    let nonce = next_nonce(&args.transaction, &sender_address, url).await?;
+    let transaction = Transaction::new(
+        transaction_type,
+        sender_address,
+        nonce,
+        args.transaction.max_fee,
+    )?;
+    let transaction = sign(transaction, keystore)?;
 
-    match tendermint::broadcast(transaction_serialized, url).await {
-        Ok(_) => Ok(transaction),
-        Err(e) => bail!("DEPLOY: Error sending out transaction: {e}"),
-    }
+    send_transaction(transaction, &args.transaction, url)
+        .await
+        .map_err(|e| anyhow!("DEPLOY: Error sending out transaction: {e}"))
 }
 
-async fn do_invoke(args: InvokeArgs, url: &str) -> Result<Transaction> {
+async fn do_invoke(args: InvokeArgs, url: &str, keystore: &Path) -> Result<Value> {
+    let calldata = match &args.args {
+        Some(arguments) => load_abi(&args.abi, &args.address, url)
+            .await?
+            .encode_inputs(&args.function, &serde_json::from_str(arguments)?)?,
+        None => args.inputs.unwrap_or_default(),
+    };
     let transaction_type = TransactionType::Invoke {
         address: args.address,
-        function: args.function,
-        inputs: args.inputs,
+        entry_point_selector: selector(&args.function),
+        calldata,
     };
 
-    let transaction = Transaction::with_type(transaction_type)?;
-    let transaction_serialized = bincode::serialize(&transaction)?;
+    let nonce = next_nonce(&args.transaction, &args.sender, url).await?;
+    let transaction = Transaction::new(
+        transaction_type,
+        args.sender,
+        nonce,
+        args.transaction.max_fee,
+    )?;
+    let transaction = sign(transaction, keystore)?;
+
+    send_transaction(transaction, &args.transaction, url)
+        .await
+        .map_err(|e| anyhow!("INVOKE: Error sending out transaction: {e}"))
+}
 
-    match broadcast(transaction_serialized, url).await {
-        Ok(_) => Ok(transaction),
-        Err(e) => bail!("INVOKE: Error sending out transaction: {e}"),
+/// Run a function without sending a transaction. When its arguments are given as JSON, or an ABI
+/// is given, the return data is decoded following the contract ABI.
+async fn do_call(args: CallArgs, url: &str) -> Result<Value> {
+    let abi = match (&args.args, &args.abi) {
+        (None, None) => None,
+        _ => Some(load_abi(&args.abi, &args.address, url).await?),
+    };
+    let calldata = match (&abi, &args.args) {
+        (Some(abi), Some(arguments)) => {
+            abi.encode_inputs(&args.function, &serde_json::from_str(arguments)?)?
+        }
+        _ => args.inputs.unwrap_or_default(),
+    };
+
+    let query = Query::Call {
+        address: args.address,
+        entry_point_selector: selector(&args.function),
+        calldata,
+    };
+    match (tendermint::query(query, url).await?, abi) {
+        (QueryResponse::Call { retdata }, Some(abi)) => {
+            let retdata: Vec<Felt252> = retdata
+                .iter()
+                .map(|value| parse_felt(value))
+                .collect::<Result<_>>()?;
+            abi.decode_outputs(&args.function, &retdata)
+        }
+        (response, _) => Ok(serde_json::to_value(response)?),
+    }
+}
+
+/// ABI of the given compiled contract or, if there is none, of the class deployed at the address.
+async fn load_abi(path: &Option<PathBuf>, address: &Felt252, url: &str) -> Result<Abi> {
+    if let Some(path) = path {
+        return Abi::from_program(&fs::read_to_string(path)?);
+    }
+
+    let query = Query::ClassHashAt {
+        address: address.clone(),
+    };
+    let class_hash = match tendermint::query(query, url).await? {
+        QueryResponse::ClassHash { class_hash } => parse_felt(&class_hash)?,
+        response => bail!("Unexpected response to class hash query: {response:?}"),
+    };
+    match tendermint::query(Query::Class { class_hash }, url).await? {
+        QueryResponse::Class { program } => Abi::from_program(&program),
+        response => bail!("Unexpected response to class query: {response:?}"),
     }
 }
@@ -1,26 +1,89 @@
-use anyhow::{bail, ensure, Result};
-use lib::Transaction;
-use tendermint_rpc::{query::Query, Client, HttpClient, Order};
+use anyhow::{anyhow, bail, ensure, Result};
+use lib::error::ErrorCode;
+use lib::execution::{ExecutionOutcome, Receipt};
+use lib::query::{Query, QueryResponse};
+use lib::{parse_felt, Transaction};
+use std::time::Duration;
+use tendermint_rpc::{query::Query as RpcQuery, Client, HttpClient, Order};
 use tracing::debug;
 
+/// A transaction included in a block, along with the result of delivering it.
+#[derive(Debug)]
+pub struct CommittedTransaction {
+    pub transaction: Transaction,
+    /// Code returned by DeliverTx, zero if the transaction succeeded.
+    pub code: u32,
+    pub receipt: Receipt,
+}
+
 pub async fn broadcast(transaction: Vec<u8>, url: &str) -> Result<()> {
     let client = HttpClient::new(url).unwrap();
 
     let response = client.broadcast_tx_sync(transaction).await?;
 
     debug!("Response from CheckTx: {:?}", response);
-    match response.code {
+    check_tx_result(response.code, &response.log)
+}
+
+/// Broadcast a transaction and wait, up to `timeout`, for the result of delivering it in a committed block.
+pub async fn broadcast_and_wait(
+    transaction: Transaction,
+    url: &str,
+    timeout: Duration,
+) -> Result<CommittedTransaction> {
+    let client = HttpClient::new(url)?;
+
+    let response = tokio::time::timeout(
+        timeout,
+        client.broadcast_tx_commit(bincode::serialize(&transaction)?),
+    )
+    .await
+    .map_err(|_| {
+        anyhow!(
+            "Transaction {} was not committed after {} seconds",
+            transaction.transaction_hash,
+            timeout.as_secs()
+        )
+    })??;
+
+    debug!("Response from broadcast_tx_commit: {:?}", response);
+    check_tx_result(response.check_tx.code, &response.check_tx.log)?;
+
+    // the node answers once the block is committed, so the receipt is stored by then
+    let query = Query::Receipt {
+        transaction_hash: parse_felt(&transaction.transaction_hash)?,
+    };
+    let receipt = match self::query(query, url).await? {
+        QueryResponse::Receipt(receipt) => receipt,
+        response => bail!("Unexpected response to receipt query: {response:?}"),
+    };
+
+    Ok(CommittedTransaction {
+        transaction,
+        code: response.deliver_tx.code.value(),
+        receipt,
+    })
+}
+
+fn check_tx_result(code: tendermint::abci::Code, log: &str) -> Result<()> {
+    match code {
         tendermint::abci::Code::Ok => Ok(()),
-        tendermint::abci::Code::Err(code) => {
-            bail!("Error executing transaction {}: {}", code, response.log)
-        }
+        tendermint::abci::Code::Err(code) => match ErrorCode::from_code(code.get()) {
+            Some(error_code) => bail!(
+                "Transaction rejected with code {} ({}): {}",
+                code,
+                error_code,
+                log
+            ),
+            None => bail!("Error executing transaction {}: {}", code, log),
+        },
     }
 }
 
-pub async fn get_transaction(tx_id: &str, url: &str) -> Result<Transaction> {
+pub async fn get_transaction(tx_id: &str, url: &str) -> Result<CommittedTransaction> {
     let client = HttpClient::new(url)?;
     // todo: this index key might have to be a part of the shared lib so that both the CLI and the ABCI can be in sync
-    let query = Query::contains("app.tx_id", tx_id);
+    let query = RpcQuery::eq("app.tx_id", tx_id);
 
     let response = client
         .tx_search(query, false, 1, 1, Order::Ascending)
@@ -33,8 +96,34 @@ pub async fn get_transaction(tx_id: &str, url: &str) -> Result<Transaction> {
         tx_id
     );
 
-    let tx_bytes = response.txs.into_iter().next().unwrap().tx;
-    let transaction: Transaction = bincode::deserialize(&tx_bytes)?;
+    let tx_response = response.txs.into_iter().next().unwrap();
+    let transaction: Transaction = bincode::deserialize(&tx_response.tx)?;
+    let receipt = Receipt {
+        transaction_hash: transaction.transaction_hash.clone(),
+        block_height: tx_response.height.value().try_into()?,
+        index: tx_response.index.try_into()?,
+        outcome: ExecutionOutcome::decode(&tx_response.tx_result.data)?,
+    };
+
+    Ok(CommittedTransaction {
+        transaction,
+        code: tx_response.tx_result.code.value(),
+        receipt,
+    })
+}
+
+pub async fn query(query: Query, url: &str) -> Result<QueryResponse> {
+    let client = HttpClient::new(url)?;
+
+    let response = client
+        .abci_query(Some(query.path()), query.data()?, None, false)
+        .await?;
 
-    Ok(transaction)
+    debug!("Response from Query: {:?}", response);
+    match response.code {
+        tendermint::abci::Code::Ok => QueryResponse::decode(&response.value),
+        tendermint::abci::Code::Err(code) => {
+            bail!("Error running query {}: {}", code, response.log)
+        }
+    }
 }
@@ -0,0 +1,60 @@
+use felt::Felt252;
+use starknet_rs::utils::calculate_sn_keccak;
+
+/// Entry point called on the sender account to decide whether it accepts an invoke transaction.
+pub const VALIDATE_ENTRY_POINT: &str = "__validate__";
+
+/// Entry point called on the sender account to run the calls of an invoke transaction.
+pub const EXECUTE_ENTRY_POINT: &str = "__execute__";
+
+/// Entry point called on the sender account to decide whether it accepts a declare transaction.
+pub const VALIDATE_DECLARE_ENTRY_POINT: &str = "__validate_declare__";
+
+/// Entry point called on a newly deployed account to decide whether it accepts its deploy transaction.
+pub const VALIDATE_DEPLOY_ENTRY_POINT: &str = "__validate_deploy__";
+
+/// Entry point run when a contract is deployed.
+pub const CONSTRUCTOR_ENTRY_POINT: &str = "constructor";
+
+/// ERC-20 entry point called on the fee token to pay fees.
+pub const TRANSFER_ENTRY_POINT: &str = "transfer";
+
+/// ERC-20 entry point called on the fee token to check that an account can pay a fee.
+pub const BALANCE_OF_ENTRY_POINT: &str = "balanceOf";
+
+/// Selector of the entry point named `function`.
+pub fn selector(function: &str) -> Felt252 {
+    Felt252::from_bytes_be(&calculate_sn_keccak(function.as_bytes()))
+}
+
+/// Calldata for `__validate__` and `__execute__` on accounts following the OpenZeppelin
+/// multicall interface (see `cairo_programs/Account.json`), for a transaction made of a single call:
+/// `[call_array_len, to, selector, data_offset, data_len, calldata_len, ...calldata]`.
+pub fn execute_calldata(to: Felt252, selector: Felt252, calldata: Vec<Felt252>) -> Vec<Felt252> {
+    let mut execute_calldata = vec![
+        1.into(),
+        to,
+        selector,
+        0.into(),
+        calldata.len().into(),
+        calldata.len().into(),
+    ];
+    execute_calldata.extend(calldata);
+    execute_calldata
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_call_is_encoded_as_a_call_array() {
+        let calldata = execute_calldata(0xabcd.into(), 0x1234.into(), vec![7.into(), 8.into()]);
+
+        let expected: Vec<Felt252> = vec![1, 0xabcd, 0x1234, 0, 2, 2, 7, 8]
+            .into_iter()
+            .map(Felt252::from)
+            .collect();
+        assert_eq!(calldata, expected);
+    }
+}
@@ -0,0 +1,68 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// Changes a committed block made to the Starknet state, following the `StateUpdate` of the
+/// Starknet API. It is stored by the application for each height, with felts as hex strings.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct StateUpdate {
+    pub block_number: i64,
+    /// App hash after executing the block.
+    pub new_root: String,
+    /// App hash before executing the block.
+    pub old_root: String,
+    /// Hashes of the transactions delivered in the block, in execution order.
+    pub transactions: Vec<String>,
+    pub state_diff: StateDiff,
+}
+
+/// Writes of a block, sorted by address and key so the same block always encodes the same way.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct StateDiff {
+    pub storage_diffs: Vec<StorageDiff>,
+    /// Cairo 0 classes declared in the block.
+    pub deprecated_declared_classes: Vec<String>,
+    /// Cairo 1 classes declared in the block.
+    pub declared_classes: Vec<DeclaredClass>,
+    pub deployed_contracts: Vec<DeployedContract>,
+    pub nonces: Vec<NonceUpdate>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct StorageDiff {
+    pub address: String,
+    pub storage_entries: Vec<StorageEntry>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct StorageEntry {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DeclaredClass {
+    pub class_hash: String,
+    pub compiled_class_hash: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DeployedContract {
+    pub address: String,
+    pub class_hash: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NonceUpdate {
+    pub contract_address: String,
+    pub nonce: String,
+}
+
+impl StateUpdate {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
@@ -0,0 +1,77 @@
+use std::fmt;
+
+/// Codes returned by the ABCI application when it rejects a transaction, so clients can tell
+/// the reasons apart. Zero is reserved by ABCI for success.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ErrorCode {
+    /// The payload could not be decoded as a `Transaction`.
+    MalformedTransaction = 1,
+    /// The transaction hash is not consistent with its contents.
+    IntegrityCheckFailed = 2,
+    /// The contract class sent in a declare transaction could not be parsed.
+    InvalidContractClass = 3,
+    /// A deploy transaction references a class hash that has not been declared.
+    UnknownClassHash = 4,
+    /// An invoke transaction targets an address where no contract is deployed.
+    ContractNotDeployed = 5,
+    /// The transaction was included in a block but its execution failed.
+    ExecutionFailed = 6,
+    /// The sender account rejected the transaction in `__validate__`, `__validate_declare__` or `__validate_deploy__`.
+    ValidationFailed = 7,
+    /// The transaction nonce is not the next one of the sender account.
+    InvalidNonce = 8,
+    /// The fee of the transaction is higher than the max fee it was sent with.
+    MaxFeeExceeded = 9,
+    /// The sender does not hold enough of the fee token to pay the max fee of the transaction.
+    InsufficientBalance = 10,
+    /// A deploy transaction uses, or an invoke transaction calls, a Cairo 1 class, which can't be executed yet.
+    UnsupportedClass = 11,
+}
+
+impl ErrorCode {
+    pub fn from_code(code: u32) -> Option<Self> {
+        let error_code = match code {
+            1 => ErrorCode::MalformedTransaction,
+            2 => ErrorCode::IntegrityCheckFailed,
+            3 => ErrorCode::InvalidContractClass,
+            4 => ErrorCode::UnknownClassHash,
+            5 => ErrorCode::ContractNotDeployed,
+            6 => ErrorCode::ExecutionFailed,
+            7 => ErrorCode::ValidationFailed,
+            8 => ErrorCode::InvalidNonce,
+            9 => ErrorCode::MaxFeeExceeded,
+            10 => ErrorCode::InsufficientBalance,
+            11 => ErrorCode::UnsupportedClass,
+            _ => return None,
+        };
+
+        Some(error_code)
+    }
+}
+
+impl From<ErrorCode> for u32 {
+    fn from(code: ErrorCode) -> Self {
+        code as u32
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            ErrorCode::MalformedTransaction => "malformed transaction",
+            ErrorCode::IntegrityCheckFailed => "integrity check failed",
+            ErrorCode::InvalidContractClass => "invalid contract class",
+            ErrorCode::UnknownClassHash => "unknown class hash",
+            ErrorCode::ContractNotDeployed => "contract not deployed",
+            ErrorCode::ExecutionFailed => "execution failed",
+            ErrorCode::ValidationFailed => "validation failed",
+            ErrorCode::InvalidNonce => "invalid nonce",
+            ErrorCode::MaxFeeExceeded => "max fee exceeded",
+            ErrorCode::InsufficientBalance => "insufficient balance",
+            ErrorCode::UnsupportedClass => "unsupported class",
+        };
+
+        write!(f, "{description}")
+    }
+}
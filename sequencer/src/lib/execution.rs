@@ -0,0 +1,121 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Outcome of executing a transaction in `deliver_tx`, sent back in `ResponseDeliverTx.data`.
+/// Since CometBFT hashes that data into the block, its encoding must be deterministic.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ExecutionOutcome {
+    pub status: ExecutionStatus,
+    /// Error that made the transaction fail, if it did.
+    pub revert_reason: Option<String>,
+    /// Values returned by the executed function, as hex strings.
+    pub retdata: Vec<String>,
+    /// Starknet events emitted during the execution, in emission order.
+    pub events: Vec<Event>,
+    pub execution_resources: ExecutionResources,
+    /// Fee charged to the sender, in the smallest unit of the fee token.
+    pub actual_fee: u64,
+    /// Address of the contract created by a deploy transaction.
+    pub contract_address: Option<String>,
+    /// Hash of the class created by a declare transaction.
+    pub class_hash: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExecutionStatus {
+    #[default]
+    Succeeded,
+    Reverted,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Event {
+    pub from_address: String,
+    pub keys: Vec<String>,
+    pub data: Vec<String>,
+}
+
+/// Cairo VM resources used by an execution.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ExecutionResources {
+    pub n_steps: usize,
+    pub n_memory_holes: usize,
+    pub builtin_instance_counter: BTreeMap<String, usize>,
+}
+
+/// Record of what happened when a transaction was executed, stored by the application once its block is committed.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Receipt {
+    pub transaction_hash: String,
+    pub block_height: i64,
+    /// Position of the transaction in its block.
+    pub index: usize,
+    #[serde(flatten)]
+    pub outcome: ExecutionOutcome,
+}
+
+impl ExecutionOutcome {
+    pub fn succeeded() -> Self {
+        Self::default()
+    }
+
+    pub fn reverted(reason: String) -> Self {
+        Self {
+            status: ExecutionStatus::Reverted,
+            revert_reason: Some(reason),
+            ..Self::default()
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status == ExecutionStatus::Succeeded
+    }
+
+    /// Cairo steps used by the execution.
+    pub fn steps(&self) -> usize {
+        self.execution_resources.n_steps
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+impl ExecutionResources {
+    /// Add the resources used by another execution to these.
+    pub fn merge(&mut self, other: &ExecutionResources) {
+        self.n_steps += other.n_steps;
+        self.n_memory_holes += other.n_memory_holes;
+        for (builtin, count) in other.builtin_instance_counter.iter() {
+            *self
+                .builtin_instance_counter
+                .entry(builtin.clone())
+                .or_default() += count;
+        }
+    }
+}
+
+impl ExecutionStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExecutionStatus::Succeeded => "succeeded",
+            ExecutionStatus::Reverted => "reverted",
+        }
+    }
+}
+
+impl Receipt {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
@@ -0,0 +1,126 @@
+use crate::execution::ExecutionResources;
+use crate::serde_felt;
+use felt::Felt252;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Fee weights are kept in millionths of L1 gas, so fees are computed with integers only.
+const WEIGHT_PRECISION: u128 = 1_000_000;
+
+/// L1 gas charged per unit of each Cairo resource, keyed by `n_steps` and builtin names, as in the
+/// `cairo_resource_fee_weights` of Starknet's general config.
+/// The resource with the highest cost determines the gas used by a transaction.
+#[derive(Clone, Debug, Default)]
+pub struct FeeWeights(HashMap<String, u128>);
+
+impl FeeWeights {
+    /// Take the weights of the config, rounded to millionths of gas.
+    pub fn new(weights: &HashMap<String, f64>) -> Self {
+        Self(
+            weights
+                .iter()
+                .map(|(resource, weight)| {
+                    let weight = (weight * WEIGHT_PRECISION as f64).round() as u128;
+                    (resource.clone(), weight)
+                })
+                .collect(),
+        )
+    }
+
+    fn weight(&self, resource: &str) -> u128 {
+        self.0.get(resource).copied().unwrap_or_default()
+    }
+}
+
+/// Fee settings of the chain.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeeConfig {
+    /// Price of a unit of L1 gas, in the smallest unit of the fee token. Zero disables fee charging.
+    pub gas_price: u64,
+    /// ERC-20 contract fees are paid with.
+    #[serde(with = "serde_felt::option")]
+    pub fee_token_address: Option<Felt252>,
+    /// Address receiving the fees.
+    #[serde(with = "serde_felt")]
+    pub sequencer_address: Felt252,
+}
+
+/// L1 gas used by an execution.
+pub fn calculate_l1_gas(resources: &ExecutionResources, weights: &FeeWeights) -> u64 {
+    let builtins = resources
+        .builtin_instance_counter
+        .iter()
+        .map(|(builtin, count)| (builtin.as_str(), *count));
+
+    let cost = std::iter::once(("n_steps", resources.n_steps))
+        .chain(builtins)
+        .map(|(resource, usage)| weights.weight(resource) * usage as u128)
+        .max()
+        .unwrap_or_default();
+    u64::try_from(cost.div_ceil(WEIGHT_PRECISION)).unwrap_or(u64::MAX)
+}
+
+/// Fee charged for an execution, in the smallest unit of the fee token.
+pub fn calculate_fee(resources: &ExecutionResources, weights: &FeeWeights, gas_price: u64) -> u64 {
+    calculate_l1_gas(resources, weights).saturating_mul(gas_price)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Default weights of Starknet's general config.
+    fn weights() -> FeeWeights {
+        let weights = [
+            ("n_steps", 0.01),
+            ("output_builtin", 0.0),
+            ("pedersen_builtin", 0.32),
+            ("range_check_builtin", 0.16),
+            ("ecdsa_builtin", 20.48),
+            ("bitwise_builtin", 0.64),
+            ("ec_op_builtin", 10.24),
+        ];
+        FeeWeights::new(
+            &weights
+                .into_iter()
+                .map(|(resource, weight)| (resource.to_string(), weight))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn most_expensive_resource_sets_the_gas() {
+        let resources = ExecutionResources {
+            n_steps: 1000,
+            n_memory_holes: 0,
+            builtin_instance_counter: [
+                ("range_check_builtin".to_string(), 10),
+                ("pedersen_builtin".to_string(), 50),
+            ]
+            .into_iter()
+            .collect(),
+        };
+
+        // 50 pedersen instances cost 16 gas, more than the 10 used by the steps
+        assert_eq!(calculate_l1_gas(&resources, &weights()), 16);
+        assert_eq!(calculate_fee(&resources, &weights(), 100), 1600);
+    }
+
+    #[test]
+    fn unused_resources_are_free() {
+        assert_eq!(
+            calculate_fee(&ExecutionResources::default(), &weights(), 100),
+            0
+        );
+    }
+
+    #[test]
+    fn partial_gas_is_rounded_up() {
+        let resources = ExecutionResources {
+            n_steps: 1001,
+            ..Default::default()
+        };
+
+        assert_eq!(calculate_l1_gas(&resources, &weights()), 11);
+    }
+}
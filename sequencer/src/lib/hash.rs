@@ -0,0 +1,138 @@
+use crate::account::selector;
+use crate::parse_felt;
+use felt::Felt252;
+use once_cell::sync::Lazy;
+use starknet_crypto::{pedersen_hash, FieldElement};
+
+/// Chain id mixed into every transaction hash, so transactions signed for this network are not valid elsewhere.
+pub static CHAIN_ID: Lazy<Felt252> = Lazy::new(|| short_string("SN_TENDERMINT"));
+
+/// Storage addresses are taken modulo this bound, `2^251 - 256`.
+static ADDRESS_BOUND: Lazy<Felt252> = Lazy::new(|| {
+    parse_felt("0x7ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff00")
+        .expect("Invalid address bound")
+});
+
+/// Prefixes used by Starknet to tell transaction types apart when hashing them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransactionHashPrefix {
+    Declare,
+    DeployAccount,
+    Invoke,
+}
+
+impl TransactionHashPrefix {
+    fn as_felt(&self) -> Felt252 {
+        match self {
+            TransactionHashPrefix::Declare => short_string("declare"),
+            TransactionHashPrefix::DeployAccount => short_string("deploy_account"),
+            TransactionHashPrefix::Invoke => short_string("invoke"),
+        }
+    }
+}
+
+/// Hash of a transaction, as computed by Starknet for version 1 and 2 transactions:
+/// `h(prefix, version, address, 0, h(calldata), max_fee, chain_id, nonce, ...additional_data)`.
+/// Declare v2 transactions pass their compiled class hash as additional data.
+pub fn calculate_transaction_hash(
+    prefix: TransactionHashPrefix,
+    version: u64,
+    address: &Felt252,
+    calldata: &[Felt252],
+    max_fee: u64,
+    nonce: &Felt252,
+    additional_data: &[Felt252],
+) -> Felt252 {
+    let mut elements = vec![
+        prefix.as_felt(),
+        version.into(),
+        address.clone(),
+        0.into(), // entry point selector, unused since version 1
+        compute_hash_on_elements(calldata),
+        max_fee.into(),
+        CHAIN_ID.clone(),
+        nonce.clone(),
+    ];
+    elements.extend_from_slice(additional_data);
+
+    compute_hash_on_elements(&elements)
+}
+
+/// Pedersen hash chain over `data`, ending with its length: `h(h(h(0, data[0]), data[1]), ..., len(data))`.
+pub fn compute_hash_on_elements(data: &[Felt252]) -> Felt252 {
+    let hash = data
+        .iter()
+        .map(felt_to_field_element)
+        .chain(std::iter::once(FieldElement::from(data.len() as u64)))
+        .fold(FieldElement::ZERO, |hash, element| {
+            pedersen_hash(&hash, &element)
+        });
+
+    field_element_to_felt(&hash)
+}
+
+/// Address where a Cairo contract keeps the storage variable `name` for the given keys:
+/// `h(...h(h(sn_keccak(name), keys[0]), keys[1])..., keys[n])`, taken modulo `2^251 - 256`.
+pub fn storage_var_address(name: &str, keys: &[Felt252]) -> Felt252 {
+    let address = keys
+        .iter()
+        .fold(felt_to_field_element(&selector(name)), |address, key| {
+            pedersen_hash(&address, &felt_to_field_element(key))
+        });
+
+    // felts are below 2^252, so a single subtraction is enough
+    let address = field_element_to_felt(&address);
+    if address >= *ADDRESS_BOUND {
+        address - ADDRESS_BOUND.clone()
+    } else {
+        address
+    }
+}
+
+pub fn felt_to_field_element(value: &Felt252) -> FieldElement {
+    let bytes = value.to_bytes_be();
+    let mut padded = [0u8; 32];
+    padded[32 - bytes.len()..].copy_from_slice(&bytes);
+    // felts are always below the field modulus
+    FieldElement::from_bytes_be(&padded).expect("Value out of field range")
+}
+
+pub fn field_element_to_felt(value: &FieldElement) -> Felt252 {
+    Felt252::from_bytes_be(&value.to_bytes_be())
+}
+
+/// Cairo short string: the ASCII bytes of `value` read as a big endian number.
+fn short_string(value: &str) -> Felt252 {
+    Felt252::from_bytes_be(value.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_on_no_elements_is_hash_of_zeros() {
+        assert_eq!(
+            compute_hash_on_elements(&[]),
+            parse_felt("0x49ee3eba8c1600700ee1b87eb599f16716b0b1022947733551fde4050ca6804")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn transaction_hash_depends_on_nonce() {
+        let hash = |nonce: u64| {
+            calculate_transaction_hash(
+                TransactionHashPrefix::Invoke,
+                1,
+                &0xabcd.into(),
+                &[1.into(), 2.into()],
+                0,
+                &nonce.into(),
+                &[],
+            )
+        };
+
+        assert_ne!(hash(0), hash(1));
+    }
+}
@@ -1,18 +1,49 @@
-use anyhow::{ensure, Result};
+use account::execute_calldata;
+use anyhow::{anyhow, bail, ensure, Result};
 use felt::Felt252;
+use hash::{calculate_transaction_hash, TransactionHashPrefix};
 use num_traits::{Num, Zero};
 use serde::{Deserialize, Serialize};
+use sierra::SierraClass;
 use starknet_rs::{
+    core::contract_address::starknet_contract_address::compute_class_hash,
     hash_utils::calculate_contract_address, services::api::contract_class::ContractClass,
     utils::Address,
 };
 use uuid::Uuid;
 
+pub mod account;
+pub mod block;
+pub mod error;
+pub mod execution;
+pub mod fee;
+pub mod hash;
+pub mod query;
+pub mod serde_felt;
+pub mod sierra;
+
+/// Version of the transactions built and accepted by the sequencer. Since version 1, transactions
+/// are sent from an account contract which checks their signature.
+pub const TRANSACTION_VERSION: u64 = 1;
+
+/// Version of declare transactions carrying a Cairo 1 class.
+pub const DECLARE_V2_VERSION: u64 = 2;
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Transaction {
     pub transaction_type: TransactionType,
     pub transaction_hash: String,
     pub id: String,
+    /// Account sending the transaction. For deploy account transactions, the address of the account being deployed.
+    #[serde(with = "serde_felt")]
+    pub sender_address: Felt252,
+    #[serde(with = "serde_felt")]
+    pub nonce: Felt252,
+    pub max_fee: u64,
+    pub version: u64,
+    /// Signature over the transaction hash, checked by the sender account.
+    #[serde(with = "serde_felt::vec")]
+    pub signature: Vec<Felt252>,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -20,85 +51,270 @@ pub enum TransactionType {
     /// Create new contract class.
     Declare { program: String },
 
-    /// Create an instance of a contract which will have storage assigned. (Accounts are a contract themselves)
+    /// Create new Cairo 1 contract class, given as its Sierra JSON along with the CASM it compiles to,
+    /// which is what gets executed. The transaction hash commits to the CASM through its compiled class hash.
+    DeclareV2 {
+        sierra_class: String,
+        casm_class: String,
+        #[serde(with = "serde_felt")]
+        compiled_class_hash: Felt252,
+    },
+
+    /// Create an instance of an account contract, which will have storage assigned.
+    /// The account runs its constructor and then validates the transaction in `__validate_deploy__`.
     DeployAccount {
-        class_hash: String,
-        salt: i32,
-        inputs: Option<Vec<i32>>,
+        #[serde(with = "serde_felt")]
+        class_hash: Felt252,
+        #[serde(with = "serde_felt")]
+        salt: Felt252,
+        #[serde(with = "serde_felt::vec")]
+        constructor_calldata: Vec<Felt252>,
     },
 
-    /// Execute a function from a deployed contract.
+    /// Execute a function from a deployed contract, sending the call through the sender account.
+    /// The account validates the transaction in `__validate__` and forwards the call in `__execute__`,
+    /// so the called contract sees the account as its caller.
     Invoke {
-        address: String,
-        function: String,
-        inputs: Option<Vec<i32>>,
+        #[serde(with = "serde_felt")]
+        address: Felt252,
+        /// Selector of the called function, the `sn_keccak` of its name (see `account::selector`).
+        #[serde(with = "serde_felt")]
+        entry_point_selector: Felt252,
+        #[serde(with = "serde_felt::vec")]
+        calldata: Vec<Felt252>,
     },
 }
 
 impl Transaction {
-    pub fn with_type(transaction_type: TransactionType) -> Result<Transaction> {
-        Ok(Transaction {
-            transaction_hash: transaction_type.compute_and_hash()?,
+    /// Build an unsigned transaction, computing its hash.
+    pub fn new(
+        transaction_type: TransactionType,
+        sender_address: Felt252,
+        nonce: Felt252,
+        max_fee: u64,
+    ) -> Result<Transaction> {
+        let version = transaction_type.version();
+        let mut transaction = Transaction {
             transaction_type,
+            transaction_hash: String::new(),
             id: Uuid::new_v4().to_string(),
-        })
+            sender_address,
+            nonce,
+            max_fee,
+            version,
+            signature: Vec::new(),
+        };
+        transaction.transaction_hash = felt_to_hex(&transaction.compute_hash()?);
+
+        Ok(transaction)
+    }
+
+    /// Starknet hash of the transaction, computed over everything but its id and signature.
+    pub fn compute_hash(&self) -> Result<Felt252> {
+        let prefix = match self.transaction_type {
+            TransactionType::Declare { .. } | TransactionType::DeclareV2 { .. } => {
+                TransactionHashPrefix::Declare
+            }
+            TransactionType::DeployAccount { .. } => TransactionHashPrefix::DeployAccount,
+            TransactionType::Invoke { .. } => TransactionHashPrefix::Invoke,
+        };
+
+        Ok(calculate_transaction_hash(
+            prefix,
+            self.version,
+            &self.sender_address,
+            &self.transaction_type.calldata()?,
+            self.max_fee,
+            &self.nonce,
+            &self.transaction_type.additional_hash_data(),
+        ))
     }
 
-    /// Verify that the transaction id is consistent with its contents, by checking its sha256 hash.
+    /// Transaction hash as a felt, which is the message signed by the sender.
+    pub fn hash(&self) -> Result<Felt252> {
+        parse_felt(&self.transaction_hash)
+    }
+
+    /// Verify that the transaction hash is consistent with its contents. The signature itself is
+    /// checked by the sender account when the transaction is executed.
     pub fn assert_integrity(&self) -> Result<()> {
         ensure!(
-            self.transaction_hash == self.transaction_type.compute_and_hash()?,
-            "Corrupted transaction: Inconsistent transaction id"
+            self.version == self.transaction_type.version(),
+            "Unsupported transaction version {}",
+            self.version
+        );
+        ensure!(
+            self.transaction_hash == felt_to_hex(&self.compute_hash()?),
+            "Corrupted transaction: Inconsistent transaction hash"
         );
 
+        if let TransactionType::DeployAccount { .. } = self.transaction_type {
+            ensure!(
+                self.sender_address == self.transaction_type.contract_address()?,
+                "Sender address does not match the address of the deployed account"
+            );
+        }
+
         Ok(())
     }
 }
 
 impl TransactionType {
-    // TODO: Rename this and/or structure the code differently
-    pub fn compute_and_hash(&self) -> Result<String> {
+    /// Name of every transaction type, as returned by `name`.
+    pub const NAMES: [&'static str; 4] = ["declare", "declare_v2", "deploy_account", "invoke"];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransactionType::Declare { .. } => "declare",
+            TransactionType::DeclareV2 { .. } => "declare_v2",
+            TransactionType::DeployAccount { .. } => "deploy_account",
+            TransactionType::Invoke { .. } => "invoke",
+        }
+    }
+
+    /// Version transactions of this type are sent with.
+    pub fn version(&self) -> u64 {
+        match self {
+            TransactionType::DeclareV2 { .. } => DECLARE_V2_VERSION,
+            _ => TRANSACTION_VERSION,
+        }
+    }
+
+    /// Fields hashed after the nonce, only used by declare v2 to commit to the compiled class hash.
+    fn additional_hash_data(&self) -> Vec<Felt252> {
+        match self {
+            TransactionType::DeclareV2 {
+                compiled_class_hash,
+                ..
+            } => vec![compiled_class_hash.clone()],
+            _ => vec![],
+        }
+    }
+
+    /// Calldata the transaction hash commits to, which is also what the sender account validates:
+    /// the declared class hash for declares, `[class_hash, salt, ...constructor_calldata]` for deploys
+    /// and the calldata of the account's `__execute__` for invokes.
+    pub fn calldata(&self) -> Result<Vec<Felt252>> {
         match self {
             TransactionType::Declare { program } => {
                 let contract_class = ContractClass::try_from(program.as_str())?;
                 // This function requires cairo_programs/contracts.json to exist as it uses that cairo program to compute the hash
-                let contract_hash = starknet_rs::core::contract_address::starknet_contract_address::compute_class_hash(&contract_class)?;
-                Ok(format!(
-                    "{}{}",
-                    "0x",
-                    hex::encode(contract_hash.to_bytes_be())
-                ))
+                Ok(vec![compute_class_hash(&contract_class)?])
+            }
+            TransactionType::DeclareV2 { sierra_class, .. } => {
+                Ok(vec![
+                    SierraClass::from_json(sierra_class)?.compute_class_hash()?
+                ])
             }
             TransactionType::DeployAccount {
                 class_hash,
                 salt,
-                inputs,
+                constructor_calldata,
             } => {
-                let constructor_calldata = match &inputs {
-                    Some(vec) => vec.iter().map(|&n| n.into()).collect(),
-                    None => Vec::new(),
-                };
-
-                let contract_address = calculate_contract_address(
-                    &Address((*salt).into()),
-                    &felt::Felt252::from_str_radix(&class_hash[2..], 16).unwrap(), // TODO: Handle these errors better
-                    &constructor_calldata,
-                    Address(Felt252::zero()), // TODO: Deployer address is hardcoded to 0 in starknet-in-rust, ask why
-                )?;
-
-                Ok(format!(
-                    "{}{}",
-                    "0x",
-                    hex::encode(contract_address.to_bytes_be())
-                ))
+                let mut calldata = vec![class_hash.clone(), salt.clone()];
+                calldata.extend(constructor_calldata.iter().cloned());
+                Ok(calldata)
             }
             TransactionType::Invoke {
                 address,
-                function,
-                inputs,
-            } => Ok(format!(
-                "Invoked {function} with inputs {inputs:?} for contract in address {address}"
+                entry_point_selector,
+                calldata,
+            } => Ok(execute_calldata(
+                address.clone(),
+                entry_point_selector.clone(),
+                calldata.clone(),
             )),
         }
     }
+
+    /// Address where a deploy account transaction deploys the account.
+    pub fn contract_address(&self) -> Result<Felt252> {
+        let TransactionType::DeployAccount {
+            class_hash,
+            salt,
+            constructor_calldata,
+        } = self
+        else {
+            bail!("Only deploy account transactions deploy a contract");
+        };
+
+        Ok(calculate_contract_address(
+            &Address(salt.clone()),
+            class_hash,
+            constructor_calldata,
+            Address(Felt252::zero()), // TODO: Deployer address is hardcoded to 0 in starknet-in-rust, ask why
+        )?)
+    }
+}
+
+/// Parse a felt from a `0x`-prefixed hex string, a decimal string or a `'quoted'` short string.
+pub fn parse_felt(value: &str) -> Result<Felt252> {
+    if let Some(short_string) = value
+        .strip_prefix('\'')
+        .and_then(|value| value.strip_suffix('\''))
+    {
+        return short_string_to_felt(short_string);
+    }
+
+    let felt = match value.strip_prefix("0x") {
+        Some(hex) => Felt252::from_str_radix(hex, 16),
+        None => Felt252::from_str_radix(value, 10),
+    };
+
+    felt.map_err(|_| anyhow!("Could not parse felt: {value}"))
+}
+
+/// Encode a Cairo short string, at most 31 ASCII characters packed big-endian into a felt.
+pub fn short_string_to_felt(value: &str) -> Result<Felt252> {
+    ensure!(
+        value.is_ascii() && value.len() <= 31,
+        "Short strings must have at most 31 ASCII characters: '{value}'"
+    );
+
+    Ok(Felt252::from_bytes_be(value.as_bytes()))
+}
+
+/// Format a felt as a `0x`-prefixed hex string.
+pub fn felt_to_hex(value: &Felt252) -> String {
+    format!("0x{}", value.to_str_radix(16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn felts_parse_from_hex_decimal_and_short_strings() {
+        assert_eq!(parse_felt("0x1f").unwrap(), Felt252::from(31));
+        assert_eq!(parse_felt("31").unwrap(), Felt252::from(31));
+        assert_eq!(parse_felt("'AB'").unwrap(), Felt252::from(0x4142));
+        assert!(parse_felt("'this string is longer than 31 chars'").is_err());
+        assert!(parse_felt("0xzz").is_err());
+    }
+
+    #[test]
+    fn transactions_serialize_felts_as_hex() {
+        let transaction = Transaction::new(
+            TransactionType::DeployAccount {
+                class_hash: 0xabc.into(),
+                salt: 1111.into(),
+                constructor_calldata: vec![1.into(), 0xff.into()],
+            },
+            0x123.into(),
+            2.into(),
+            0,
+        )
+        .unwrap();
+
+        let json = serde_json::to_value(&transaction).unwrap();
+        assert_eq!(json["sender_address"], "0x123");
+        assert_eq!(
+            json["transaction_type"]["DeployAccount"]["constructor_calldata"][1],
+            "0xff"
+        );
+
+        let decoded: Transaction =
+            bincode::deserialize(&bincode::serialize(&transaction).unwrap()).unwrap();
+        assert_eq!(decoded.compute_hash().unwrap(), transaction.hash().unwrap());
+    }
 }
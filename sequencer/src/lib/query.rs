@@ -0,0 +1,194 @@
+use crate::block::StateUpdate;
+use crate::execution::Receipt;
+use crate::{felt_to_hex, parse_felt};
+use anyhow::{bail, Result};
+use felt::Felt252;
+use serde::{Deserialize, Serialize};
+
+/// Queries served by the ABCI `query` hook. Each one maps to a path in `RequestQuery.path`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Query {
+    /// `/class/<class_hash>`: source of a declared contract class.
+    Class { class_hash: Felt252 },
+
+    /// `/contract/<address>/class_hash`: class hash of a deployed contract.
+    ClassHashAt { address: Felt252 },
+
+    /// `/contract/<address>/storage/<key>`: value stored by a contract under a storage address.
+    StorageAt { address: Felt252, key: Felt252 },
+
+    /// `/contract/<address>/nonce`: nonce the next transaction of an account has to use,
+    /// counting the transactions of the account waiting in the mempool.
+    Nonce { address: Felt252 },
+
+    /// `/tx/<transaction_hash>/receipt`: receipt of a committed transaction.
+    Receipt { transaction_hash: Felt252 },
+
+    /// `/block/<height>/state_update`: changes made to the state by a committed block.
+    StateUpdate { block_number: i64 },
+
+    /// `/contract/<address>/call/<entry_point_selector>`: run a function without committing its writes.
+    /// The calldata travels in `RequestQuery.data`.
+    Call {
+        address: Felt252,
+        entry_point_selector: Felt252,
+        calldata: Vec<Felt252>,
+    },
+}
+
+/// Result of running a `Query` against the committed state.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryResponse {
+    Class { program: String },
+    ClassHash { class_hash: String },
+    Storage { value: String },
+    Nonce { nonce: String },
+    Call { retdata: Vec<String> },
+    Receipt(Receipt),
+    StateUpdate(StateUpdate),
+}
+
+impl Query {
+    pub fn path(&self) -> String {
+        match self {
+            Query::Class { class_hash } => format!("/class/{}", felt_to_hex(class_hash)),
+            Query::ClassHashAt { address } => {
+                format!("/contract/{}/class_hash", felt_to_hex(address))
+            }
+            Query::StorageAt { address, key } => format!(
+                "/contract/{}/storage/{}",
+                felt_to_hex(address),
+                felt_to_hex(key)
+            ),
+            Query::Nonce { address } => format!("/contract/{}/nonce", felt_to_hex(address)),
+            Query::Receipt { transaction_hash } => {
+                format!("/tx/{}/receipt", felt_to_hex(transaction_hash))
+            }
+            Query::StateUpdate { block_number } => format!("/block/{block_number}/state_update"),
+            Query::Call {
+                address,
+                entry_point_selector,
+                ..
+            } => format!(
+                "/contract/{}/call/{}",
+                felt_to_hex(address),
+                felt_to_hex(entry_point_selector)
+            ),
+        }
+    }
+
+    /// Payload sent along with the path in `RequestQuery.data`.
+    pub fn data(&self) -> Result<Vec<u8>> {
+        match self {
+            Query::Call { calldata, .. } => {
+                let calldata: Vec<String> = calldata.iter().map(felt_to_hex).collect();
+                Ok(serde_json::to_vec(&calldata)?)
+            }
+            _ => Ok(vec![]),
+        }
+    }
+
+    pub fn from_request(path: &str, data: &[u8]) -> Result<Self> {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+        let query = match segments.as_slice() {
+            ["class", class_hash] => Query::Class {
+                class_hash: parse_felt(class_hash)?,
+            },
+            ["contract", address, "class_hash"] => Query::ClassHashAt {
+                address: parse_felt(address)?,
+            },
+            ["contract", address, "storage", key] => Query::StorageAt {
+                address: parse_felt(address)?,
+                key: parse_felt(key)?,
+            },
+            ["contract", address, "nonce"] => Query::Nonce {
+                address: parse_felt(address)?,
+            },
+            ["tx", transaction_hash, "receipt"] => Query::Receipt {
+                transaction_hash: parse_felt(transaction_hash)?,
+            },
+            ["block", block_number, "state_update"] => Query::StateUpdate {
+                block_number: block_number.parse()?,
+            },
+            ["contract", address, "call", entry_point_selector] => {
+                let calldata: Vec<String> = serde_json::from_slice(data)?;
+                Query::Call {
+                    address: parse_felt(address)?,
+                    entry_point_selector: parse_felt(entry_point_selector)?,
+                    calldata: calldata
+                        .iter()
+                        .map(|value| parse_felt(value))
+                        .collect::<Result<_>>()?,
+                }
+            }
+            _ => bail!("Unknown query path: {path}"),
+        };
+
+        Ok(query)
+    }
+}
+
+impl QueryResponse {
+    /// Serialize the response to be sent as `ResponseQuery.value`.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_round_trip() {
+        let queries = vec![
+            Query::Class {
+                class_hash: 0x1234.into(),
+            },
+            Query::ClassHashAt {
+                address: 0xabcd.into(),
+            },
+            Query::StorageAt {
+                address: 0xabcd.into(),
+                key: 7.into(),
+            },
+            Query::Nonce {
+                address: 0xabcd.into(),
+            },
+            Query::Receipt {
+                transaction_hash: 0x1234.into(),
+            },
+            Query::StateUpdate { block_number: 12 },
+            Query::Call {
+                address: 0xabcd.into(),
+                entry_point_selector: 0x1234.into(),
+                calldata: vec![1.into(), 0xff.into()],
+            },
+        ];
+
+        for query in queries {
+            let data = query.data().unwrap();
+            assert_eq!(Query::from_request(&query.path(), &data).unwrap(), query);
+        }
+    }
+
+    #[test]
+    fn hashes_are_parsed_however_they_are_spelled() {
+        let receipt = |hash| Query::from_request(&format!("/tx/{hash}/receipt"), &[]).unwrap();
+
+        assert_eq!(receipt("0x0ABC"), receipt("0xabc"));
+        assert_eq!(receipt("0x0ABC").path(), "/tx/0xabc/receipt");
+    }
+
+    #[test]
+    fn unknown_paths_are_rejected() {
+        assert!(Query::from_request("/contract/0x1/balance", &[]).is_err());
+        assert!(Query::from_request("", &[]).is_err());
+    }
+}
@@ -0,0 +1,125 @@
+//! Serde helpers encoding felts as `0x`-prefixed hex strings, so every node reads and writes the
+//! same bytes no matter how `Felt252` represents itself internally.
+//! Use as `#[serde(with = "serde_felt")]`, or `serde_felt::vec` for lists of felts.
+//!
+//! Only the canonical encoding is accepted: lowercase hex without leading zeros, below the field
+//! prime. Otherwise the same transaction could be sent as different bytes, and be included twice.
+//! Inputs typed by users, like the genesis file or RPC parameters, use `serde_felt::lenient` instead,
+//! which accepts anything `parse_felt` does.
+
+use crate::{felt_to_hex, parse_felt};
+use felt::Felt252;
+use num_traits::Num;
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+pub fn serialize<S: Serializer>(value: &Felt252, serializer: S) -> Result<S::Ok, S::Error> {
+    felt_to_hex(value).serialize(serializer)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Felt252, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_canonical(&value).map_err(D::Error::custom)
+}
+
+/// Parse a felt from its canonical encoding, the one `felt_to_hex` produces.
+fn parse_canonical(value: &str) -> Result<Felt252, String> {
+    let error = || format!("Not a canonical felt: {value}");
+    let felt = value
+        .strip_prefix("0x")
+        .and_then(|hex| Felt252::from_str_radix(hex, 16).ok())
+        .ok_or_else(error)?;
+
+    // re-encoding rejects uppercase digits, leading zeros and values reduced modulo the prime
+    if felt_to_hex(&felt) != value {
+        return Err(error());
+    }
+    Ok(felt)
+}
+
+pub mod vec {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(values: &[Felt252], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(values.iter().map(felt_to_hex))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Felt252>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|value| parse_canonical(value).map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+/// Same encoding for optional felts, with `null` for a missing one.
+pub mod option {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        value: &Option<Felt252>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        value.as_ref().map(felt_to_hex).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Felt252>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|value| parse_canonical(&value).map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
+/// Same encoding, but reading felts in any format `parse_felt` accepts.
+pub mod lenient {
+    use super::*;
+
+    pub use super::serialize;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Felt252, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        parse_felt(&value).map_err(D::Error::custom)
+    }
+
+    pub mod vec {
+        use super::*;
+
+        pub use crate::serde_felt::vec::serialize;
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Vec<Felt252>, D::Error> {
+            Vec::<String>::deserialize(deserializer)?
+                .iter()
+                .map(|value| parse_felt(value).map_err(D::Error::custom))
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_canonical_felts_are_accepted() {
+        assert_eq!(parse_canonical("0x1f"), Ok(Felt252::from(31)));
+        assert_eq!(parse_canonical("0x0"), Ok(Felt252::from(0)));
+
+        for value in [
+            "0x1F",
+            "0x01f",
+            "31",
+            "'AB'",
+            "0x",
+            "1f",
+            // the field prime itself, which would be read as zero
+            "0x800000000000011000000000000000000000000000000000000000000000001",
+        ] {
+            assert!(parse_canonical(value).is_err(), "{value} was accepted");
+        }
+    }
+}
@@ -0,0 +1,318 @@
+use crate::hash::{felt_to_field_element, field_element_to_felt};
+use crate::parse_felt;
+use anyhow::{anyhow, ensure, Result};
+use felt::Felt252;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use serde_json::Value;
+use starknet_crypto::{poseidon_hash_many, FieldElement};
+use starknet_rs::utils::calculate_sn_keccak;
+
+/// Cairo 1 contract class in its Sierra form, as produced by `starknet-compile` and sent in
+/// declare v2 transactions along with the CASM it compiles down to.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SierraClass {
+    pub sierra_program: Vec<String>,
+    pub contract_class_version: String,
+    pub entry_points_by_type: SierraEntryPoints,
+    /// ABI, either already flattened into a string or as the JSON array output by the compiler.
+    /// It is kept as written since its exact text is part of the class hash.
+    pub abi: Box<RawValue>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SierraEntryPoints {
+    #[serde(rename = "EXTERNAL")]
+    pub external: Vec<SierraEntryPoint>,
+    #[serde(rename = "L1_HANDLER")]
+    pub l1_handler: Vec<SierraEntryPoint>,
+    #[serde(rename = "CONSTRUCTOR")]
+    pub constructor: Vec<SierraEntryPoint>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SierraEntryPoint {
+    pub selector: String,
+    pub function_idx: u64,
+}
+
+impl SierraClass {
+    pub fn from_json(class: &str) -> Result<Self> {
+        serde_json::from_str(class).map_err(|e| anyhow!("Could not parse Sierra class: {e}"))
+    }
+
+    /// Whether a contract class JSON is a Sierra class rather than a Cairo 0 one.
+    pub fn is_sierra(class: &str) -> bool {
+        serde_json::from_str::<Value>(class)
+            .map(|class| class.get("sierra_program").is_some())
+            .unwrap_or(false)
+    }
+
+    /// Class hash of a Sierra class, as computed by Starknet:
+    /// `poseidon("CONTRACT_CLASS_V<version>", external, l1_handlers, constructors, sn_keccak(abi), poseidon(program))`,
+    /// where each group of entry points is hashed as `poseidon(selector_0, function_idx_0, ...)`.
+    pub fn compute_class_hash(&self) -> Result<Felt252> {
+        let version = format!("CONTRACT_CLASS_V{}", self.contract_class_version);
+        let abi = calculate_sn_keccak(self.flattened_abi()?.as_bytes());
+        let program = self
+            .sierra_program
+            .iter()
+            .map(|value| parse_felt(value).map(|felt| felt_to_field_element(&felt)))
+            .collect::<Result<Vec<_>>>()?;
+
+        let hash = poseidon_hash_many(&[
+            felt_to_field_element(&Felt252::from_bytes_be(version.as_bytes())),
+            hash_entry_points(&self.entry_points_by_type.external)?,
+            hash_entry_points(&self.entry_points_by_type.l1_handler)?,
+            hash_entry_points(&self.entry_points_by_type.constructor)?,
+            felt_to_field_element(&Felt252::from_bytes_be(&abi)),
+            poseidon_hash_many(&program),
+        ]);
+
+        Ok(field_element_to_felt(&hash))
+    }
+
+    /// ABI as the string that gets hashed. Starknet hashes the ABI as written by Python's
+    /// `json.dumps`, which separates items with `", "`, keys from values with `": "` and escapes
+    /// non-ASCII characters.
+    fn flattened_abi(&self) -> Result<String> {
+        if let Ok(abi) = serde_json::from_str::<String>(self.abi.get()) {
+            return Ok(abi);
+        }
+
+        let mut abi = String::new();
+        let (mut in_string, mut escaped) = (false, false);
+        for c in self.abi.get().chars() {
+            if in_string {
+                match c {
+                    _ if escaped => escaped = false,
+                    '\\' => escaped = true,
+                    '"' => in_string = false,
+                    _ => {}
+                }
+                if c.is_ascii() {
+                    abi.push(c);
+                } else {
+                    for unit in c.encode_utf16(&mut [0; 2]) {
+                        abi.push_str(&format!("\\u{unit:04x}"));
+                    }
+                }
+                continue;
+            }
+
+            match c {
+                '"' => {
+                    in_string = true;
+                    abi.push(c);
+                }
+                ',' => abi.push_str(", "),
+                ':' => abi.push_str(": "),
+                _ if c.is_whitespace() => {}
+                _ => abi.push(c),
+            }
+        }
+
+        Ok(abi)
+    }
+}
+
+/// CASM a Cairo 1 class compiles down to, as produced by `starknet-sierra-compile`. Declare v2
+/// transactions carry it so the compiled class hash they commit to can be checked.
+#[derive(Clone, Debug, Deserialize)]
+pub struct CasmClass {
+    pub bytecode: Vec<String>,
+    /// How the bytecode is split into segments for hashing, absent when it is hashed as a whole.
+    #[serde(default)]
+    pub bytecode_segment_lengths: Option<BytecodeSegments>,
+    pub entry_points_by_type: CasmEntryPoints,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum BytecodeSegments {
+    Leaf(usize),
+    Node(Vec<BytecodeSegments>),
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct CasmEntryPoints {
+    #[serde(rename = "EXTERNAL")]
+    pub external: Vec<CasmEntryPoint>,
+    #[serde(rename = "L1_HANDLER")]
+    pub l1_handler: Vec<CasmEntryPoint>,
+    #[serde(rename = "CONSTRUCTOR")]
+    pub constructor: Vec<CasmEntryPoint>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CasmEntryPoint {
+    pub selector: String,
+    pub offset: u64,
+    pub builtins: Vec<String>,
+}
+
+impl CasmClass {
+    pub fn from_json(class: &str) -> Result<Self> {
+        serde_json::from_str(class).map_err(|e| anyhow!("Could not parse CASM class: {e}"))
+    }
+
+    /// Compiled class hash, as computed by Starknet:
+    /// `poseidon("COMPILED_CLASS_V1", external, l1_handlers, constructors, bytecode)`, where each group of
+    /// entry points is hashed as `poseidon(selector_0, offset_0, poseidon(builtins_0), ...)`.
+    pub fn compute_compiled_class_hash(&self) -> Result<Felt252> {
+        let bytecode = self
+            .bytecode
+            .iter()
+            .map(|value| parse_felt(value).map(|felt| felt_to_field_element(&felt)))
+            .collect::<Result<Vec<_>>>()?;
+        let segments = self
+            .bytecode_segment_lengths
+            .clone()
+            .unwrap_or(BytecodeSegments::Leaf(bytecode.len()));
+        let (length, bytecode_hash) = hash_bytecode(&bytecode, &segments)?;
+        ensure!(
+            length == bytecode.len(),
+            "Bytecode segments cover {length} of its {} values",
+            bytecode.len()
+        );
+
+        let hash = poseidon_hash_many(&[
+            felt_to_field_element(&Felt252::from_bytes_be(b"COMPILED_CLASS_V1")),
+            hash_casm_entry_points(&self.entry_points_by_type.external)?,
+            hash_casm_entry_points(&self.entry_points_by_type.l1_handler)?,
+            hash_casm_entry_points(&self.entry_points_by_type.constructor)?,
+            bytecode_hash,
+        ]);
+
+        Ok(field_element_to_felt(&hash))
+    }
+}
+
+/// Hash of the bytecode segment starting at the beginning of `bytecode`, along with its length.
+/// Leaves are hashed as `poseidon(values)`, and nodes as `1 + poseidon(length_0, hash_0, ...)`.
+fn hash_bytecode(
+    bytecode: &[FieldElement],
+    segments: &BytecodeSegments,
+) -> Result<(usize, FieldElement)> {
+    match segments {
+        BytecodeSegments::Leaf(length) => {
+            let values = bytecode
+                .get(..*length)
+                .ok_or_else(|| anyhow!("Bytecode segments are longer than the bytecode"))?;
+            Ok((*length, poseidon_hash_many(values)))
+        }
+        BytecodeSegments::Node(segments) => {
+            let (mut length, mut elements) = (0, Vec::new());
+            for segment in segments {
+                let (segment_length, hash) = hash_bytecode(&bytecode[length..], segment)?;
+                length += segment_length;
+                elements.push(FieldElement::from(segment_length as u64));
+                elements.push(hash);
+            }
+            Ok((length, poseidon_hash_many(&elements) + FieldElement::ONE))
+        }
+    }
+}
+
+fn hash_casm_entry_points(entry_points: &[CasmEntryPoint]) -> Result<FieldElement> {
+    let mut elements = Vec::new();
+    for entry_point in entry_points {
+        let builtins: Vec<_> = entry_point
+            .builtins
+            .iter()
+            .map(|builtin| felt_to_field_element(&Felt252::from_bytes_be(builtin.as_bytes())))
+            .collect();
+        elements.push(felt_to_field_element(&parse_felt(&entry_point.selector)?));
+        elements.push(FieldElement::from(entry_point.offset));
+        elements.push(poseidon_hash_many(&builtins));
+    }
+
+    Ok(poseidon_hash_many(&elements))
+}
+
+fn hash_entry_points(entry_points: &[SierraEntryPoint]) -> Result<FieldElement> {
+    let mut elements = Vec::new();
+    for entry_point in entry_points {
+        elements.push(felt_to_field_element(&parse_felt(&entry_point.selector)?));
+        elements.push(FieldElement::from(entry_point.function_idx));
+    }
+
+    Ok(poseidon_hash_many(&elements))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::fs;
+
+    /// Account contract from the Cairo compiler's test data, in its Sierra and CASM forms.
+    const SIERRA_ACCOUNT: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../cairo_programs/cairo1/account.contract_class.json"
+    );
+    const CASM_ACCOUNT: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../cairo_programs/cairo1/account.compiled_contract_class.json"
+    );
+
+    #[test]
+    fn sierra_classes_are_told_apart_from_cairo_0_classes() {
+        let sierra = json!({
+            "sierra_program": ["0x1", "0x2"],
+            "contract_class_version": "0.1.0",
+            "entry_points_by_type": {"EXTERNAL": [], "L1_HANDLER": [], "CONSTRUCTOR": []},
+            "abi": []
+        })
+        .to_string();
+
+        assert!(SierraClass::is_sierra(&sierra));
+        assert!(SierraClass::from_json(&sierra).is_ok());
+        assert!(!SierraClass::is_sierra(r#"{"program": {}, "abi": []}"#));
+    }
+
+    #[test]
+    fn abis_are_flattened_with_python_separators() {
+        let class = |abi: &str| SierraClass {
+            sierra_program: vec![],
+            contract_class_version: "0.1.0".to_string(),
+            entry_points_by_type: SierraEntryPoints::default(),
+            abi: RawValue::from_string(abi.to_string()).unwrap(),
+        };
+
+        let abi =
+            "[\n  {\"type\": \"function\",\"name\":\"a, b: \\\"é\\\"\", \"inputs\": [1,2]}\n]";
+        assert_eq!(
+            class(abi).flattened_abi().unwrap(),
+            r#"[{"type": "function", "name": "a, b: \"\u00e9\"", "inputs": [1, 2]}]"#
+        );
+        assert_eq!(
+            class(r#""[{\"type\": \"event\"}]""#)
+                .flattened_abi()
+                .unwrap(),
+            r#"[{"type": "event"}]"#
+        );
+    }
+
+    #[test]
+    fn sierra_class_hashes_match_starknet() {
+        let class = SierraClass::from_json(&fs::read_to_string(SIERRA_ACCOUNT).unwrap()).unwrap();
+
+        assert_eq!(
+            class.compute_class_hash().unwrap(),
+            parse_felt("0x547d530a50d0340bf68c1ab0a882789786129eb7c9fa3a8893840db6d030bdd")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn compiled_class_hashes_match_starknet() {
+        let class = CasmClass::from_json(&fs::read_to_string(CASM_ACCOUNT).unwrap()).unwrap();
+
+        assert_eq!(
+            class.compute_compiled_class_hash().unwrap(),
+            parse_felt("0x4b552d087e9633fbecf2185d144fafca55e6581502c0fc93953c143757dc8bf")
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use anyhow::Result;
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, State};
+use axum::http::{header, Method};
+use axum::routing::post;
+use axum::{Json, Router, Server};
+use clap::Parser;
+use felt::Felt252;
+use lib::parse_felt;
+use node::Node;
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tower_http::cors::{Any, CorsLayer};
+use tower_http::timeout::TimeoutLayer;
+use tracing::{debug, info};
+use tracing_subscriber::{filter::LevelFilter, util::SubscriberInitExt};
+
+mod methods;
+mod node;
+
+/// Largest request body accepted, enough for declare transactions carrying big classes.
+const MAX_BODY_SIZE: usize = 32 * 1024 * 1024;
+
+/// Time clients have to send the headers of a request, so idle connections don't pile up.
+const HEADER_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time a request has to be read and answered, including the calls made to the sequencer.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Parser)]
+#[clap(author, version, about)]
+struct Cli {
+    /// Address the JSON-RPC server listens on.
+    #[clap(long, default_value = "127.0.0.1:5050")]
+    listen: SocketAddr,
+
+    /// CometBFT RPC endpoint of the sequencer.
+    #[clap(long, env = "SEQUENCER_URL", default_value = "http://127.0.0.1:26657")]
+    url: String,
+
+    /// Address reported as the sequencer of every block.
+    #[clap(long, default_value = "0", value_parser = parse_felt)]
+    sequencer_address: Felt252,
+
+    /// Increase output logging verbosity to DEBUG level.
+    #[clap(short, long)]
+    verbose: bool,
+
+    /// Suppress all output logging (overrides --verbose).
+    #[clap(short, long)]
+    quiet: bool,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli: Cli = Cli::parse();
+    let log_level = if cli.quiet {
+        LevelFilter::OFF
+    } else if cli.verbose {
+        LevelFilter::DEBUG
+    } else {
+        LevelFilter::INFO
+    };
+
+    let subscriber = tracing_subscriber::fmt()
+        // Use a more compact, abbreviated log format
+        .compact()
+        .with_max_level(log_level)
+        // Display the thread ID an event was recorded on
+        .with_thread_ids(true)
+        // Don't display the event's target (module path)
+        .with_target(false)
+        // Build the subscriber
+        .finish();
+
+    subscriber.init();
+
+    let node = Arc::new(Node::new(&cli.url, cli.sequencer_address)?);
+    let server = Server::try_bind(&cli.listen)?
+        .http1_header_read_timeout(HEADER_READ_TIMEOUT)
+        .serve(router(node).into_make_service());
+    info!("Starknet JSON-RPC listening on {}", cli.listen);
+
+    server.await?;
+    Ok(())
+}
+
+/// JSON-RPC requests are POSTed to any path. Browsers are allowed to call the gateway from any origin.
+fn router(node: Arc<Node>) -> Router {
+    Router::new()
+        .route("/", post(handle))
+        .route("/*path", post(handle))
+        .with_state(node)
+        .layer(DefaultBodyLimit::max(MAX_BODY_SIZE))
+        .layer(TimeoutLayer::new(REQUEST_TIMEOUT))
+        .layer(
+            CorsLayer::new()
+                .allow_origin(Any)
+                .allow_methods([Method::POST])
+                .allow_headers([header::CONTENT_TYPE]),
+        )
+}
+
+async fn handle(State(node): State<Arc<Node>>, body: Bytes) -> Json<Value> {
+    debug!("Request of {} bytes", body.len());
+    Json(methods::handle(&node, &body).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use lib::felt_to_hex;
+    use lib::hash::CHAIN_ID;
+    use tower::ServiceExt;
+
+    fn request(method: Method, body: impl Into<Body>) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri("/rpc")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body.into())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn requests_are_answered_within_the_body_limit() {
+        let node = Arc::new(Node::new("http://127.0.0.1:26657", Felt252::from(0)).unwrap());
+        let chain_id = r#"{"jsonrpc": "2.0", "id": 1, "method": "starknet_chainId"}"#;
+
+        let answered = router(node.clone())
+            .oneshot(request(Method::POST, chain_id))
+            .await
+            .unwrap();
+        let too_large = router(node.clone())
+            .oneshot(request(Method::POST, vec![b' '; MAX_BODY_SIZE + 1]))
+            .await
+            .unwrap();
+        let not_posted = router(node)
+            .oneshot(request(Method::GET, Body::empty()))
+            .await
+            .unwrap();
+
+        assert_eq!(answered.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(answered.into_body()).await.unwrap();
+        let response: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(response["result"], felt_to_hex(&CHAIN_ID));
+        assert_eq!(too_large.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(not_posted.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
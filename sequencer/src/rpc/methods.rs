@@ -0,0 +1,785 @@
+use crate::node::{block_hash_to_felt, Node};
+use base64::engine::general_purpose;
+use base64::Engine;
+use felt::Felt252;
+use flate2::read::GzDecoder;
+use futures::future::join_all;
+use lib::error::ErrorCode;
+use lib::execution::ExecutionStatus;
+use lib::hash::CHAIN_ID;
+use lib::query::{Query, QueryResponse};
+use lib::{
+    felt_to_hex, parse_felt, serde_felt, Transaction, TransactionType, DECLARE_V2_VERSION,
+    TRANSACTION_VERSION,
+};
+use num_traits::{ToPrimitive, Zero};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use serde_json::{json, Value};
+use std::io::Read;
+
+/// JSON-RPC 2.0 error codes.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// Error codes defined by the Starknet JSON-RPC specification.
+const CONTRACT_NOT_FOUND: i64 = 20;
+const BLOCK_NOT_FOUND: i64 = 24;
+const TXN_HASH_NOT_FOUND: i64 = 25;
+const CLASS_HASH_NOT_FOUND: i64 = 28;
+const CONTRACT_ERROR: i64 = 40;
+const INVALID_CONTRACT_CLASS: i64 = 50;
+const INVALID_TRANSACTION_NONCE: i64 = 52;
+const INSUFFICIENT_MAX_FEE: i64 = 53;
+const INSUFFICIENT_ACCOUNT_BALANCE: i64 = 54;
+const VALIDATION_FAILURE: i64 = 55;
+const UNSUPPORTED_CONTRACT_CLASS_VERSION: i64 = 62;
+const UNEXPECTED_ERROR: i64 = 63;
+
+/// Blocks are final as soon as CometBFT commits them.
+const ACCEPTED_ON_L2: &str = "ACCEPTED_ON_L2";
+
+#[derive(Debug)]
+pub struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    /// Error for a failed node request, using the code of the transaction rejection if there is one.
+    fn from_node(error: anyhow::Error, default_code: i64) -> Self {
+        let code = match error.downcast_ref::<ErrorCode>() {
+            Some(ErrorCode::InvalidContractClass) => INVALID_CONTRACT_CLASS,
+            Some(ErrorCode::UnknownClassHash) => CLASS_HASH_NOT_FOUND,
+            Some(ErrorCode::ContractNotDeployed) => CONTRACT_NOT_FOUND,
+            Some(ErrorCode::ValidationFailed) => VALIDATION_FAILURE,
+            Some(ErrorCode::InvalidNonce) => INVALID_TRANSACTION_NONCE,
+            Some(ErrorCode::MaxFeeExceeded) => INSUFFICIENT_MAX_FEE,
+            Some(ErrorCode::InsufficientBalance) => INSUFFICIENT_ACCOUNT_BALANCE,
+            Some(ErrorCode::UnsupportedClass) => UNSUPPORTED_CONTRACT_CLASS_VERSION,
+            Some(_) => UNEXPECTED_ERROR,
+            None => default_code,
+        };
+
+        Self::new(code, format!("{error:#}"))
+    }
+}
+
+/// Handle a JSON-RPC request body, which may hold a single call or a batch of them.
+pub async fn handle(node: &Node, body: &[u8]) -> Value {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Array(requests)) if !requests.is_empty() => Value::Array(
+            join_all(requests.iter().map(|request| handle_request(node, request))).await,
+        ),
+        Ok(request) => handle_request(node, &request).await,
+        Err(e) => response(Value::Null, Err(RpcError::new(PARSE_ERROR, e))),
+    }
+}
+
+async fn handle_request(node: &Node, request: &Value) -> Value {
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let Some(method) = request.get("method").and_then(Value::as_str) else {
+        return response(id, Err(RpcError::new(INVALID_REQUEST, "Missing method")));
+    };
+    let params = Params(request.get("params").cloned().unwrap_or(Value::Null));
+
+    response(id, dispatch(node, method, &params).await)
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+        Err(error) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": {"code": error.code, "message": error.message},
+        }),
+    }
+}
+
+async fn dispatch(node: &Node, method: &str, params: &Params) -> Result<Value, RpcError> {
+    match method {
+        "starknet_chainId" => Ok(json!(felt_to_hex(&CHAIN_ID))),
+        "starknet_blockNumber" => Ok(json!(block_number(node).await?)),
+        "starknet_getBlockWithTxHashes" => {
+            get_block_with_tx_hashes(node, params.get(0, "block_id")?).await
+        }
+        "starknet_getStateUpdate" => get_state_update(node, params.get(0, "block_id")?).await,
+        "starknet_getStorageAt" => {
+            let address: Felt = params.get(0, "contract_address")?;
+            let key: Felt = params.get(1, "key")?;
+            ensure_latest(node, params.get(2, "block_id")?).await?;
+
+            match query(
+                node,
+                Query::StorageAt {
+                    address: address.0,
+                    key: key.0,
+                },
+                CONTRACT_NOT_FOUND,
+            )
+            .await?
+            {
+                QueryResponse::Storage { value } => Ok(json!(value)),
+                response => Err(unexpected(response)),
+            }
+        }
+        "starknet_getClassHashAt" => {
+            ensure_latest(node, params.get(0, "block_id")?).await?;
+            let address: Felt = params.get(1, "contract_address")?;
+
+            match query(
+                node,
+                Query::ClassHashAt { address: address.0 },
+                CONTRACT_NOT_FOUND,
+            )
+            .await?
+            {
+                QueryResponse::ClassHash { class_hash } => Ok(json!(class_hash)),
+                response => Err(unexpected(response)),
+            }
+        }
+        "starknet_call" => {
+            let request: FunctionCall = params.get(0, "request")?;
+            ensure_latest(node, params.get(1, "block_id")?).await?;
+
+            let query_call = Query::Call {
+                address: request.contract_address,
+                entry_point_selector: request.entry_point_selector,
+                calldata: request.calldata,
+            };
+            match query(node, query_call, CONTRACT_ERROR).await? {
+                QueryResponse::Call { retdata } => Ok(json!(retdata)),
+                response => Err(unexpected(response)),
+            }
+        }
+        "starknet_getTransactionReceipt" => {
+            let transaction_hash: Felt = params.get(0, "transaction_hash")?;
+            get_transaction_receipt(node, &transaction_hash.0).await
+        }
+        "starknet_addInvokeTransaction" => {
+            let transaction = params.get(0, "invoke_transaction")?;
+            add_invoke_transaction(node, transaction).await
+        }
+        "starknet_addDeclareTransaction" => {
+            let transaction = params.get(0, "declare_transaction")?;
+            add_declare_transaction(node, transaction).await
+        }
+        "starknet_addDeployAccountTransaction" => {
+            let transaction = params.get(0, "deploy_account_transaction")?;
+            add_deploy_account_transaction(node, transaction).await
+        }
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("Method {method} is not supported"),
+        )),
+    }
+}
+
+/// Parameters of a call, given either by position or by name.
+struct Params(Value);
+
+impl Params {
+    fn get<T: DeserializeOwned>(&self, index: usize, name: &str) -> Result<T, RpcError> {
+        let value = match &self.0 {
+            Value::Array(values) => values.get(index),
+            Value::Object(values) => values.get(name),
+            _ => None,
+        }
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("Missing parameter {name}")))?;
+
+        serde_json::from_value(value.clone())
+            .map_err(|e| RpcError::new(INVALID_PARAMS, format!("Invalid parameter {name}: {e}")))
+    }
+}
+
+#[derive(Deserialize)]
+struct Felt(#[serde(with = "serde_felt::lenient")] Felt252);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BlockId {
+    Tag(BlockTag),
+    Number { block_number: u64 },
+    Hash { block_hash: String },
+}
+
+/// Pending blocks are not exposed, so they are served as the latest one.
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum BlockTag {
+    Latest,
+    Pending,
+}
+
+#[derive(Deserialize)]
+struct FunctionCall {
+    #[serde(with = "serde_felt::lenient")]
+    contract_address: Felt252,
+    #[serde(with = "serde_felt::lenient")]
+    entry_point_selector: Felt252,
+    #[serde(with = "serde_felt::lenient::vec")]
+    calldata: Vec<Felt252>,
+}
+
+#[derive(Deserialize)]
+struct InvokeTransaction {
+    #[serde(with = "serde_felt::lenient")]
+    sender_address: Felt252,
+    #[serde(with = "serde_felt::lenient::vec")]
+    calldata: Vec<Felt252>,
+    #[serde(with = "serde_felt::lenient")]
+    max_fee: Felt252,
+    #[serde(with = "serde_felt::lenient")]
+    version: Felt252,
+    #[serde(with = "serde_felt::lenient::vec")]
+    signature: Vec<Felt252>,
+    #[serde(with = "serde_felt::lenient")]
+    nonce: Felt252,
+}
+
+#[derive(Deserialize)]
+struct DeclareTransaction {
+    #[serde(with = "serde_felt::lenient")]
+    sender_address: Felt252,
+    #[serde(with = "serde_felt::lenient")]
+    max_fee: Felt252,
+    #[serde(with = "serde_felt::lenient")]
+    version: Felt252,
+    #[serde(with = "serde_felt::lenient::vec")]
+    signature: Vec<Felt252>,
+    #[serde(with = "serde_felt::lenient")]
+    nonce: Felt252,
+    contract_class: Box<RawValue>,
+    #[serde(default)]
+    compiled_class_hash: Option<String>,
+    /// CASM of the class, which the sequencer can't compile from Sierra itself. Not part of the
+    /// Starknet spec, which leaves compiling the class to the node.
+    #[serde(default)]
+    casm_class: Option<Box<RawValue>>,
+}
+
+/// Cairo 0 class as sent in declare v1 transactions, with its program gzip-compressed and base64-encoded.
+/// Parts are kept as raw JSON, since programs hold integers too big for `Value`.
+#[derive(Deserialize)]
+struct DeprecatedContractClass {
+    program: String,
+    entry_points_by_type: Box<RawValue>,
+    #[serde(default)]
+    abi: Option<Box<RawValue>>,
+}
+
+/// Cairo 0 class in the format output by the compiler.
+#[derive(Deserialize, Serialize)]
+struct CompiledContractClass {
+    abi: Option<Box<RawValue>>,
+    entry_points_by_type: Box<RawValue>,
+    program: Box<RawValue>,
+}
+
+impl DeprecatedContractClass {
+    /// Class in the format output by the Cairo 0 compiler, which declare transactions carry.
+    fn decompress(&self) -> Result<String, RpcError> {
+        let invalid = |e: String| RpcError::new(INVALID_CONTRACT_CLASS, e);
+        let compressed = general_purpose::STANDARD
+            .decode(&self.program)
+            .map_err(|e| invalid(format!("Program is not base64 encoded: {e}")))?;
+        let mut program = String::new();
+        GzDecoder::new(compressed.as_slice())
+            .read_to_string(&mut program)
+            .map_err(|e| invalid(format!("Program is not gzip compressed: {e}")))?;
+        let program = RawValue::from_string(program)
+            .map_err(|e| invalid(format!("Could not parse program: {e}")))?;
+
+        let class = CompiledContractClass {
+            abi: self.abi.clone(),
+            entry_points_by_type: self.entry_points_by_type.clone(),
+            program,
+        };
+        serde_json::to_string(&class).map_err(|e| invalid(e.to_string()))
+    }
+}
+
+#[derive(Deserialize)]
+struct DeployAccountTransaction {
+    #[serde(with = "serde_felt::lenient")]
+    max_fee: Felt252,
+    #[serde(with = "serde_felt::lenient")]
+    version: Felt252,
+    #[serde(with = "serde_felt::lenient::vec")]
+    signature: Vec<Felt252>,
+    #[serde(with = "serde_felt::lenient")]
+    nonce: Felt252,
+    #[serde(with = "serde_felt::lenient")]
+    contract_address_salt: Felt252,
+    #[serde(with = "serde_felt::lenient::vec")]
+    constructor_calldata: Vec<Felt252>,
+    #[serde(with = "serde_felt::lenient")]
+    class_hash: Felt252,
+}
+
+async fn block_number(node: &Node) -> Result<u64, RpcError> {
+    node.block_number()
+        .await
+        .map_err(|e| RpcError::from_node(e, UNEXPECTED_ERROR))
+}
+
+/// State is only kept for the last committed block, so queries can't target older ones.
+async fn ensure_latest(node: &Node, block_id: BlockId) -> Result<(), RpcError> {
+    match block_id {
+        BlockId::Tag(BlockTag::Latest | BlockTag::Pending) => Ok(()),
+        BlockId::Number {
+            block_number: number,
+        } if number == block_number(node).await? => Ok(()),
+        // only the state of the latest block is kept, so older ones are not found
+        _ => Err(RpcError::new(
+            BLOCK_NOT_FOUND,
+            "Block not found, state can only be read at the latest block",
+        )),
+    }
+}
+
+async fn query(node: &Node, query: Query, default_code: i64) -> Result<QueryResponse, RpcError> {
+    node.query(query)
+        .await
+        .map_err(|e| RpcError::from_node(e, default_code))
+}
+
+fn unexpected(response: QueryResponse) -> RpcError {
+    RpcError::new(
+        UNEXPECTED_ERROR,
+        format!("Unexpected response from the node: {response:?}"),
+    )
+}
+
+/// Height of the block, `None` for the latest one.
+fn block_height(block_id: BlockId) -> Result<Option<u64>, RpcError> {
+    match block_id {
+        BlockId::Tag(BlockTag::Latest | BlockTag::Pending) => Ok(None),
+        BlockId::Number { block_number } => Ok(Some(block_number)),
+        BlockId::Hash { block_hash } => Err(RpcError::new(
+            BLOCK_NOT_FOUND,
+            format!(
+                "Block {block_hash} can't be looked up, blocks can only be looked up by number"
+            ),
+        )),
+    }
+}
+
+async fn get_block_with_tx_hashes(node: &Node, block_id: BlockId) -> Result<Value, RpcError> {
+    let height = block_height(block_id)?;
+
+    let block = node
+        .block(height)
+        .await
+        .map_err(|e| RpcError::from_node(e, BLOCK_NOT_FOUND))?;
+    let header = &block.block.header;
+    let block_number = header.height.value();
+    let new_root = node
+        .state_root(block_number)
+        .await
+        .map_err(|e| RpcError::from_node(e, UNEXPECTED_ERROR))?;
+
+    let transactions: Vec<String> = block
+        .block
+        .data
+        .iter()
+        .filter_map(|tx| bincode::deserialize::<Transaction>(tx).ok())
+        .map(|transaction| transaction.transaction_hash)
+        .collect();
+
+    Ok(json!({
+        "status": ACCEPTED_ON_L2,
+        "block_hash": felt_to_hex(&block_hash_to_felt(&block.block_id.hash)),
+        "parent_hash": felt_to_hex(
+            &header
+                .last_block_id
+                .as_ref()
+                .map(|id| block_hash_to_felt(&id.hash))
+                .unwrap_or_default()
+        ),
+        "block_number": block_number,
+        "new_root": felt_to_hex(&new_root),
+        "timestamp": header.time.unix_timestamp(),
+        "sequencer_address": felt_to_hex(&node.sequencer_address),
+        "transactions": transactions,
+    }))
+}
+
+/// The genesis state is reported as block zero, which has no CometBFT block and so no hash.
+async fn get_state_update(node: &Node, block_id: BlockId) -> Result<Value, RpcError> {
+    let height = match block_height(block_id)? {
+        Some(height) => height,
+        None => block_number(node).await?,
+    };
+    let block_number = height
+        .try_into()
+        .map_err(|e| RpcError::new(INVALID_PARAMS, e))?;
+    let state_update =
+        match query(node, Query::StateUpdate { block_number }, BLOCK_NOT_FOUND).await? {
+            QueryResponse::StateUpdate(state_update) => state_update,
+            response => return Err(unexpected(response)),
+        };
+
+    let block_hash = if height == 0 {
+        Felt252::zero()
+    } else {
+        let block = node
+            .block(Some(height))
+            .await
+            .map_err(|e| RpcError::from_node(e, BLOCK_NOT_FOUND))?;
+        block_hash_to_felt(&block.block_id.hash)
+    };
+
+    Ok(json!({
+        "block_hash": felt_to_hex(&block_hash),
+        "new_root": state_update.new_root,
+        "old_root": state_update.old_root,
+        "state_diff": state_update.state_diff,
+    }))
+}
+
+async fn get_transaction_receipt(
+    node: &Node,
+    transaction_hash: &Felt252,
+) -> Result<Value, RpcError> {
+    let receipt = match query(
+        node,
+        Query::Receipt {
+            transaction_hash: transaction_hash.clone(),
+        },
+        TXN_HASH_NOT_FOUND,
+    )
+    .await?
+    {
+        QueryResponse::Receipt(receipt) => receipt,
+        response => return Err(unexpected(response)),
+    };
+
+    // the block holds the hash of the block and the type of the transaction
+    let height = receipt
+        .block_height
+        .try_into()
+        .map_err(|e| RpcError::new(UNEXPECTED_ERROR, e))?;
+    let block = node
+        .block(Some(height))
+        .await
+        .map_err(|e| RpcError::from_node(e, BLOCK_NOT_FOUND))?;
+    let transaction: Transaction = block
+        .block
+        .data
+        .get(receipt.index)
+        .and_then(|tx| bincode::deserialize(tx).ok())
+        .ok_or_else(|| RpcError::new(UNEXPECTED_ERROR, "Transaction not found in its block"))?;
+    let transaction_type = match transaction.transaction_type {
+        TransactionType::Declare { .. } | TransactionType::DeclareV2 { .. } => "DECLARE",
+        TransactionType::DeployAccount { .. } => "DEPLOY_ACCOUNT",
+        TransactionType::Invoke { .. } => "INVOKE",
+    };
+
+    let outcome = receipt.outcome;
+    let execution_status = match outcome.status {
+        ExecutionStatus::Succeeded => "SUCCEEDED",
+        ExecutionStatus::Reverted => "REVERTED",
+    };
+    let mut output = json!({
+        "type": transaction_type,
+        "transaction_hash": receipt.transaction_hash,
+        "actual_fee": felt_to_hex(&outcome.actual_fee.into()),
+        "status": ACCEPTED_ON_L2,
+        "finality_status": ACCEPTED_ON_L2,
+        "execution_status": execution_status,
+        "block_hash": felt_to_hex(&block_hash_to_felt(&block.block_id.hash)),
+        "block_number": height,
+        "messages_sent": [],
+        "events": outcome.events,
+    });
+    if let Some(revert_reason) = outcome.revert_reason {
+        output["revert_reason"] = json!(revert_reason);
+    }
+    if let Some(contract_address) = outcome.contract_address {
+        output["contract_address"] = json!(contract_address);
+    }
+
+    Ok(output)
+}
+
+/// Build and broadcast a transaction out of the fields of a Starknet one, which the sender
+/// signed with the same hash this sequencer computes.
+async fn submit(
+    node: &Node,
+    transaction_type: TransactionType,
+    sender_address: Felt252,
+    nonce: Felt252,
+    max_fee: &Felt252,
+    version: &Felt252,
+    signature: Vec<Felt252>,
+) -> Result<Transaction, RpcError> {
+    if *version != transaction_type.version().into() {
+        return Err(RpcError::new(
+            INVALID_PARAMS,
+            format!("Unsupported transaction version {}", felt_to_hex(version)),
+        ));
+    }
+    let max_fee = max_fee
+        .to_u64()
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "Max fee does not fit in 64 bits"))?;
+
+    let transaction = Transaction::new(transaction_type, sender_address, nonce, max_fee)
+        .map_err(|e| RpcError::new(INVALID_PARAMS, format!("{e:#}")))?;
+    let transaction = Transaction {
+        signature,
+        ..transaction
+    };
+
+    node.broadcast(&transaction)
+        .await
+        .map_err(|e| RpcError::from_node(e, UNEXPECTED_ERROR))?;
+    Ok(transaction)
+}
+
+/// Invokes carry the calldata of the account's `__execute__`, which is turned back into the
+/// single call the sequencer's invoke transactions are made of.
+async fn add_invoke_transaction(node: &Node, tx: InvokeTransaction) -> Result<Value, RpcError> {
+    let (address, entry_point_selector, calldata) = single_call(&tx.calldata).ok_or_else(|| {
+        RpcError::new(
+            INVALID_PARAMS,
+            "Only invoke transactions made of a single call are supported",
+        )
+    })?;
+    let transaction_type = TransactionType::Invoke {
+        address,
+        entry_point_selector,
+        calldata,
+    };
+
+    let transaction = submit(
+        node,
+        transaction_type,
+        tx.sender_address,
+        tx.nonce,
+        &tx.max_fee,
+        &tx.version,
+        tx.signature,
+    )
+    .await?;
+    Ok(json!({"transaction_hash": transaction.transaction_hash}))
+}
+
+/// Declare v1 transactions carry a Cairo 0 class, and declare v2 ones a Cairo 1 class.
+async fn add_declare_transaction(node: &Node, tx: DeclareTransaction) -> Result<Value, RpcError> {
+    let transaction_type = declare_transaction_type(&tx)?;
+
+    let transaction = submit(
+        node,
+        transaction_type,
+        tx.sender_address,
+        tx.nonce,
+        &tx.max_fee,
+        &tx.version,
+        tx.signature,
+    )
+    .await?;
+    let class_hash = transaction
+        .transaction_type
+        .calldata()
+        .map_err(|e| RpcError::new(INVALID_CONTRACT_CLASS, format!("{e:#}")))?;
+
+    Ok(json!({
+        "transaction_hash": transaction.transaction_hash,
+        "class_hash": felt_to_hex(&class_hash[0]),
+    }))
+}
+
+fn declare_transaction_type(tx: &DeclareTransaction) -> Result<TransactionType, RpcError> {
+    match tx.version.to_u64() {
+        Some(TRANSACTION_VERSION) => {
+            let class: DeprecatedContractClass = serde_json::from_str(tx.contract_class.get())
+                .map_err(|e| RpcError::new(INVALID_CONTRACT_CLASS, e))?;
+            Ok(TransactionType::Declare {
+                program: class.decompress()?,
+            })
+        }
+        Some(DECLARE_V2_VERSION) => {
+            let Some(compiled_class_hash) = &tx.compiled_class_hash else {
+                return Err(RpcError::new(
+                    INVALID_PARAMS,
+                    "Declare v2 transactions must include the compiled_class_hash",
+                ));
+            };
+            let Some(casm_class) = &tx.casm_class else {
+                return Err(RpcError::new(
+                    INVALID_PARAMS,
+                    "Declare v2 transactions must include the CASM of the class in casm_class",
+                ));
+            };
+            Ok(TransactionType::DeclareV2 {
+                sierra_class: tx.contract_class.get().to_string(),
+                casm_class: casm_class.get().to_string(),
+                compiled_class_hash: parse_felt(compiled_class_hash)
+                    .map_err(|e| RpcError::new(INVALID_PARAMS, e))?,
+            })
+        }
+        _ => Err(RpcError::new(
+            INVALID_PARAMS,
+            format!(
+                "Unsupported transaction version {}",
+                felt_to_hex(&tx.version)
+            ),
+        )),
+    }
+}
+
+async fn add_deploy_account_transaction(
+    node: &Node,
+    tx: DeployAccountTransaction,
+) -> Result<Value, RpcError> {
+    let transaction_type = TransactionType::DeployAccount {
+        class_hash: tx.class_hash,
+        salt: tx.contract_address_salt,
+        constructor_calldata: tx.constructor_calldata,
+    };
+    let contract_address = transaction_type
+        .contract_address()
+        .map_err(|e| RpcError::new(INVALID_PARAMS, format!("{e:#}")))?;
+
+    let transaction = submit(
+        node,
+        transaction_type,
+        contract_address.clone(),
+        tx.nonce,
+        &tx.max_fee,
+        &tx.version,
+        tx.signature,
+    )
+    .await?;
+    Ok(json!({
+        "transaction_hash": transaction.transaction_hash,
+        "contract_address": felt_to_hex(&contract_address),
+    }))
+}
+
+/// Split `__execute__` calldata following the OpenZeppelin multicall interface,
+/// `[1, to, selector, 0, len, len, ...calldata]`, into its only call.
+fn single_call(calldata: &[Felt252]) -> Option<(Felt252, Felt252, Vec<Felt252>)> {
+    let [call_array_len, to, selector, data_offset, data_len, calldata_len, calldata @ ..] =
+        calldata
+    else {
+        return None;
+    };
+
+    let len = Felt252::from(calldata.len());
+    if *call_array_len != 1.into()
+        || *data_offset != 0.into()
+        || *data_len != len
+        || *calldata_len != len
+    {
+        return None;
+    }
+
+    Some((to.clone(), selector.clone(), calldata.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use lib::account::execute_calldata;
+    use std::io::Write;
+
+    #[test]
+    fn single_calls_are_recovered_from_execute_calldata() {
+        let calldata = execute_calldata(0xabc.into(), 0x123.into(), vec![1.into(), 2.into()]);
+        assert_eq!(
+            single_call(&calldata),
+            Some((0xabc.into(), 0x123.into(), vec![1.into(), 2.into()]))
+        );
+
+        let mut multicall = calldata.clone();
+        multicall[0] = 2.into();
+        assert_eq!(single_call(&multicall), None);
+    }
+
+    #[test]
+    fn params_are_read_by_position_or_name() {
+        let positional = Params(json!(["0x1", {"block_number": 3}]));
+        let named = Params(json!({"block_id": "latest", "transaction_hash": "0x1"}));
+
+        assert_eq!(
+            positional.get::<Felt>(0, "transaction_hash").unwrap().0,
+            1.into()
+        );
+        assert!(matches!(
+            positional.get(1, "block_id").unwrap(),
+            BlockId::Number { block_number: 3 }
+        ));
+        assert!(matches!(
+            named.get(0, "block_id").unwrap(),
+            BlockId::Tag(BlockTag::Latest)
+        ));
+        assert!(named.get::<Felt>(1, "contract_address").is_err());
+    }
+
+    #[tokio::test]
+    async fn state_is_not_found_at_blocks_given_by_hash() {
+        let node = Node::new("http://127.0.0.1:26657", 0.into()).unwrap();
+        let block_id = BlockId::Hash {
+            block_hash: "0x1".to_string(),
+        };
+
+        let error = ensure_latest(&node, block_id).await.unwrap_err();
+        assert_eq!(error.code, BLOCK_NOT_FOUND);
+    }
+
+    #[test]
+    fn cairo_0_classes_are_declared_with_their_program_decompressed() {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../cairo_programs/Account.json"
+        );
+        let class: CompiledContractClass =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(class.program.get().as_bytes()).unwrap();
+        let contract_class = json!({
+            "program": general_purpose::STANDARD.encode(encoder.finish().unwrap()),
+            "entry_points_by_type": class.entry_points_by_type,
+            "abi": class.abi,
+        });
+        let declare = |version: u64| DeclareTransaction {
+            sender_address: 0x100.into(),
+            max_fee: 0.into(),
+            version: version.into(),
+            signature: vec![],
+            nonce: 0.into(),
+            contract_class: RawValue::from_string(contract_class.to_string()).unwrap(),
+            compiled_class_hash: None,
+            casm_class: None,
+        };
+
+        let Ok(TransactionType::Declare { program }) = declare_transaction_type(&declare(1)) else {
+            panic!("Declare v1 was not read as a Cairo 0 declare");
+        };
+        let declared: CompiledContractClass = serde_json::from_str(&program).unwrap();
+        assert_eq!(declared.program.get(), class.program.get());
+        assert_eq!(
+            declare_transaction_type(&declare(2)).unwrap_err().code,
+            INVALID_PARAMS
+        );
+        assert_eq!(
+            declare_transaction_type(&declare(3)).unwrap_err().code,
+            INVALID_PARAMS
+        );
+    }
+}
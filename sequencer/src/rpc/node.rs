@@ -0,0 +1,97 @@
+use anyhow::{anyhow, bail, Result};
+use felt::Felt252;
+use lib::error::ErrorCode;
+use lib::query::{Query, QueryResponse};
+use lib::Transaction;
+use tendermint_rpc::endpoint::block;
+use tendermint_rpc::{Client, HttpClient};
+use tracing::debug;
+
+/// Connection to the CometBFT node the gateway translates requests for.
+pub struct Node {
+    client: HttpClient,
+    /// Address reported as the sequencer of every block.
+    pub sequencer_address: Felt252,
+}
+
+impl Node {
+    pub fn new(url: &str, sequencer_address: Felt252) -> Result<Self> {
+        Ok(Self {
+            client: HttpClient::new(url)?,
+            sequencer_address,
+        })
+    }
+
+    /// Send a transaction to the mempool. Rejections carry the `ErrorCode` returned by CheckTx.
+    pub async fn broadcast(&self, transaction: &Transaction) -> Result<()> {
+        let response = self
+            .client
+            .broadcast_tx_sync(bincode::serialize(transaction)?)
+            .await?;
+
+        debug!("Response from CheckTx: {:?}", response);
+        match response.code {
+            tendermint::abci::Code::Ok => Ok(()),
+            tendermint::abci::Code::Err(code) => {
+                let error = anyhow!("{}", response.log);
+                match ErrorCode::from_code(code.get()) {
+                    Some(error_code) => Err(error.context(error_code)),
+                    None => Err(error),
+                }
+            }
+        }
+    }
+
+    pub async fn query(&self, query: Query) -> Result<QueryResponse> {
+        let response = self
+            .client
+            .abci_query(Some(query.path()), query.data()?, None, false)
+            .await?;
+
+        debug!("Response from Query: {:?}", response);
+        match response.code {
+            tendermint::abci::Code::Ok => QueryResponse::decode(&response.value),
+            tendermint::abci::Code::Err(_) => bail!("{}", response.log),
+        }
+    }
+
+    /// Height of the last committed block.
+    pub async fn block_number(&self) -> Result<u64> {
+        Ok(self.client.abci_info().await?.last_block_height.value())
+    }
+
+    /// Block at the given height, or the last committed one.
+    pub async fn block(&self, height: Option<u64>) -> Result<block::Response> {
+        match height {
+            Some(height) => Ok(self
+                .client
+                .block(tendermint::Height::try_from(height)?)
+                .await?),
+            None => Ok(self.client.latest_block().await?),
+        }
+    }
+
+    /// App hash after executing the block at the given height, which CometBFT only records in the
+    /// header of the next block.
+    pub async fn state_root(&self, height: u64) -> Result<Felt252> {
+        let info = self.client.abci_info().await?;
+        if info.last_block_height.value() == height {
+            return Ok(Felt252::from_bytes_be(info.last_block_app_hash.as_bytes()));
+        }
+
+        let next = self.block(Some(height + 1)).await?;
+        Ok(Felt252::from_bytes_be(
+            next.block.header.app_hash.as_bytes(),
+        ))
+    }
+}
+
+/// Felt identifying a CometBFT block hash. Block hashes are 256-bit, so the top bits are dropped
+/// to make them fit in a felt.
+pub fn block_hash_to_felt(hash: &tendermint::Hash) -> Felt252 {
+    let mut bytes = hash.as_bytes().to_vec();
+    if let Some(first) = bytes.first_mut() {
+        *first &= 0x07;
+    }
+    Felt252::from_bytes_be(&bytes)
+}